@@ -17,8 +17,44 @@
 //! The component manager exposes a RES-protocol compliant interface to allow
 //! for the querying and manipulation of the following resources:
 //!
-//! decs.components.{shard-id}.{entity-id}.{component-name} - get/set
-//! decs.components.{shard-id}.{entity-id} - get (collection)
+//! decs.components.{shard-id}.{entity-id}.{component-name} - get/set (model),
+//!   new/insert/move/delete (collection)
+//! decs.components.{shard-id}.{entity-id} - get (collection)/delete (whole entity)/
+//!   migrate (moves the entity, keeping its ID, to another shard)
+//! decs.components.{shard-id} - new (spawns an entity)/spawn (spawns from a prefab)/
+//!   batch (several component writes applied as one)
+//! decs.schemas.{component-name} - set/delete
+//! decs.prefabs.{prefab-name} - get/set/delete
+//! decs.entities.{shard-id} - get (query collection)
+//!
+//! Access is decided from the RES connection token: an entity's components are
+//! writable by the user who owns the entity (or an admin), and readable by any
+//! user owning an entity in the same shard.
+//!
+//! Admins may register a JSON Schema for a component name, after which writes of
+//! values that don't conform to it are rejected with `system.invalidParams`.
+//!
+//! Prefabs are named bundles of default component values, letting entity types be
+//! defined in data: `spawn` creates an entity from a prefab, with per-spawn overrides.
+//!
+//! The entities of a shard can be listed and filtered by the components they have, e.g.
+//! `decs.entities.{shard-id}?with=position,velocity&without=dead&offset=0&limit=25`.
+//!
+//! Collection items are kept in order: `new` adds an item at the head of the collection,
+//! `insert` adds one at a given `idx`, and `move` takes an item's `rid` and the `idx` it
+//! should end up at, which clients see as a remove followed by an add.
+//!
+//! Components are counted against their shard's capacity: before anything new is written,
+//! room for it is reserved with the shard manager, and writes to a full shard are rejected
+//! with `decs.shardFull`. Writes to a read-only shard, and anything new for a draining
+//! one, are rejected with `decs.shardClosed`.
+//!
+//! Every component model carries a `revision` number. Sets and deletes may pass an
+//! `expectedRevision`, and fail with `decs.conflict` if the component has changed since.
+//!
+//! Sets and collection `new` calls may pass a `ttl` in seconds, after which the component
+//! or item lapses. Lapsed components are swept up on the shard's game loop ticks, which
+//! removes them from the shard's indexes and publishes their removal.
 //!
 extern crate decscloud_common as decs;
 extern crate waxosuit_guest as guest;
@@ -1,13 +1,33 @@
 use crate::store;
-use codec::gateway::ResProtocolRequest;
+use codec::gateway::{ErrorCode, ResEvent, ResProtocolRequest, Rid};
+use codec::kv::KeyValue;
+use codec::users::AccessToken;
 use decscloud_common as codec;
 use guest::prelude::*;
 
+// call.decs.components.{shard-id}.new (spawns an entity)
+// call.decs.components.{shard-id}.spawn (spawns an entity from a prefab)
+// call.decs.components.{shard-id}.batch (applies several writes as one)
+// call.decs.components.{shard-id}.{entity-id}.delete (destroys an entity)
+// call.decs.components.{shard-id}.{entity-id}.migrate (moves an entity to another shard)
 // get.decs.components.{shard-id}.{entity-id}.{component-name}
 // call.decs.components.{shard-id}.{entity-id}.{component-name}.set (model)
 // call.decs.components.{shard-id}.{entity-id}.{component-name}.new (collection)
+// call.decs.components.{shard-id}.{entity-id}.{component-name}.insert (collection)
+// call.decs.components.{shard-id}.{entity-id}.{component-name}.move (collection)
 // call.decs.components.{shard-id}.{entity-id}.{component-name}.delete (collection or model)
 // access.decs.components.>
+// get.decs.entities.{shard-id}[?with=...&without=...&offset=...&limit=...]
+// access.decs.entities.{shard-id}
+// query.decs.entities.{shard-id} (query requests from RESgate after a query event)
+// decs.{shard-id}.gameloop (sweeps up components whose time to live has passed)
+// call.decs.schemas.{component-name}.set
+// call.decs.schemas.{component-name}.delete
+// access.decs.schemas.*
+// get.decs.prefabs.{prefab-name}
+// call.decs.prefabs.{prefab-name}.set
+// call.decs.prefabs.{prefab-name}.delete
+// access.decs.prefabs.*
 pub(crate) fn handle_message(
     ctx: &CapabilitiesContext,
     msg: impl Into<messaging::DeliverMessage>,
@@ -16,11 +36,66 @@ pub(crate) fn handle_message(
 
     if let Some(msg) = msg {
         match ResProtocolRequest::from(msg.subject.as_str()) {
-            ResProtocolRequest::Access(ref refid) => handle_access(ctx, &msg, refid),
-            ResProtocolRequest::Get(ref refid) => handle_get(ctx, &msg, refid),
-            ResProtocolRequest::Set(ref refid) => handle_model_set(ctx, &msg, refid),
-            ResProtocolRequest::New(ref refid) => handle_collection_new(ctx, &msg, refid),
-            ResProtocolRequest::Delete(ref refid) => handle_delete(ctx, &msg, refid),
+            ResProtocolRequest::Access(ref refid) if refid.starts_with(SCHEMA_RID_PREFIX) => {
+                with_schema_rid(ctx, &msg, refid, handle_schema_access)
+            }
+            ResProtocolRequest::Set(ref refid) if refid.starts_with(SCHEMA_RID_PREFIX) => {
+                with_schema_rid(ctx, &msg, refid, handle_schema_set)
+            }
+            ResProtocolRequest::Delete(ref refid) if refid.starts_with(SCHEMA_RID_PREFIX) => {
+                with_schema_rid(ctx, &msg, refid, handle_schema_delete)
+            }
+            ResProtocolRequest::Access(ref refid) if refid.starts_with(PREFAB_RID_PREFIX) => {
+                with_prefab_rid(ctx, &msg, refid, handle_prefab_access)
+            }
+            ResProtocolRequest::Get(ref refid) if refid.starts_with(PREFAB_RID_PREFIX) => {
+                with_prefab_rid(ctx, &msg, refid, handle_prefab_get)
+            }
+            ResProtocolRequest::Set(ref refid) if refid.starts_with(PREFAB_RID_PREFIX) => {
+                with_prefab_rid(ctx, &msg, refid, handle_prefab_set)
+            }
+            ResProtocolRequest::Delete(ref refid) if refid.starts_with(PREFAB_RID_PREFIX) => {
+                with_prefab_rid(ctx, &msg, refid, handle_prefab_delete)
+            }
+            ResProtocolRequest::Access(ref refid) if refid.starts_with(ENTITIES_RID_PREFIX) => {
+                with_entities_rid(ctx, &msg, refid, handle_entities_access)
+            }
+            ResProtocolRequest::Get(ref refid) | ResProtocolRequest::Query(ref refid, _)
+                if refid.starts_with(ENTITIES_RID_PREFIX) =>
+            {
+                with_entities_rid(ctx, &msg, refid, handle_entities_get)
+            }
+            ResProtocolRequest::Unknown if msg.subject.ends_with(GAMELOOP_SUFFIX) => {
+                handle_gameloop(ctx, &msg)
+            }
+            ResProtocolRequest::Unknown if msg.subject.starts_with(ENTITIES_QUERY_PREFIX) => {
+                with_entities_rid(
+                    ctx,
+                    &msg,
+                    &msg.subject[QUERY_SUBJECT_PREFIX.len()..],
+                    handle_entities_query,
+                )
+            }
+            ResProtocolRequest::Access(ref refid) => with_rid(ctx, &msg, refid, handle_access),
+            ResProtocolRequest::Get(ref refid) => with_rid(ctx, &msg, refid, handle_get),
+            ResProtocolRequest::Set(ref refid) => with_rid(ctx, &msg, refid, handle_model_set),
+            ResProtocolRequest::New(ref refid) => with_rid(ctx, &msg, refid, handle_new),
+            ResProtocolRequest::Delete(ref refid) => with_rid(ctx, &msg, refid, handle_delete),
+            ResProtocolRequest::Call(ref refid, ref method) if method == "spawn" => {
+                with_rid(ctx, &msg, refid, handle_spawn)
+            }
+            ResProtocolRequest::Call(ref refid, ref method) if method == "batch" => {
+                with_rid(ctx, &msg, refid, handle_batch)
+            }
+            ResProtocolRequest::Call(ref refid, ref method) if method == "migrate" => {
+                with_rid(ctx, &msg, refid, handle_entity_migrate)
+            }
+            ResProtocolRequest::Call(ref refid, ref method) if method == "insert" => {
+                with_rid(ctx, &msg, refid, handle_collection_insert)
+            }
+            ResProtocolRequest::Call(ref refid, ref method) if method == "move" => {
+                with_rid(ctx, &msg, refid, handle_collection_move)
+            }
             _ => Err("unknown service request".into()),
         }
     } else {
@@ -28,34 +103,494 @@ pub(crate) fn handle_message(
     }
 }
 
-/// Responds to a RES messaging server with the access metadata
-/// By default, we allow all access
-/// TODO: upgrade access determination
-fn handle_access(
+/// Parses the resource ID of a request and hands it to the given handler. Requests
+/// for anything other than a component, entity or shard's components, or with a
+/// malformed resource ID, are answered with an invalid parameters error rather than
+/// being dispatched
+fn with_rid(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    _rid: &str,
+    rid: &str,
+    handler: fn(&CapabilitiesContext, &messaging::BrokerMessage, &Rid) -> CallResult,
 ) -> CallResult {
-    let result = json!({
-        "result" : {
-            "get" : true,
-            "call" : "*"
+    let err = match rid.parse::<Rid>() {
+        Ok(rid @ Rid::Component { .. }) | Ok(rid @ Rid::ShardComponents(_)) => {
+            if is_write(msg) {
+                if let Some(err) = read_only_error(ctx, shard_from_rid(&rid))? {
+                    return reply(ctx, msg, &err);
+                }
+            }
+            return handler(ctx, msg, &rid);
         }
-    });
-    ctx.msg()
-        .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
+        Ok(other) => format!("not a component resource: {}", other),
+        Err(e) => e.to_string(),
+    };
+    ctx.log(&format!("Rejecting request {}: {}", msg.subject, err));
+    if !msg.reply_to.is_empty() {
+        ctx.msg().publish(
+            &msg.reply_to,
+            None,
+            &serde_json::to_vec(&codec::gateway::error_invalid_params(&err))?,
+        )?;
+    }
     Ok(vec![])
 }
 
+/// Indicates whether a request may change components, rather than only read them
+fn is_write(msg: &messaging::BrokerMessage) -> bool {
+    matches!(
+        ResProtocolRequest::from(msg.subject.as_str()),
+        ResProtocolRequest::Set(_)
+            | ResProtocolRequest::New(_)
+            | ResProtocolRequest::Delete(_)
+            | ResProtocolRequest::Call(..)
+    )
+}
+
+/// The error to reply to a write with, if the shard's state doesn't allow its components
+/// to be changed
+fn read_only_error(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+) -> codec::kv::Result<Option<serde_json::Value>> {
+    let state = store::shard_state(ctx.kv(), shard)?;
+    if state.accepts_writes() {
+        return Ok(None);
+    }
+    Ok(Some(codec::gateway::error_shard_closed(
+        &format!(
+            "Shard {} is {}, its components can't be changed",
+            shard, state
+        ),
+        state,
+    )))
+}
+
+/// The error to reply to a request that would add an entity to a shard, if the shard's
+/// state doesn't allow new entities
+fn closed_to_entities_error(
+    kv: &impl KeyValue,
+    shard: &str,
+) -> codec::kv::Result<Option<serde_json::Value>> {
+    let state = store::shard_state(kv, shard)?;
+    if state.accepts_new_components() {
+        return Ok(None);
+    }
+    Ok(Some(codec::gateway::error_shard_closed(
+        &format!("Shard {} is {} and takes no new entities", shard, state),
+        state,
+    )))
+}
+
+const SCHEMA_RID_PREFIX: &str = "decs.schemas.";
+
+/// Parses a schema resource ID and hands the component name it refers to to the given handler
+fn with_schema_rid(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &str,
+    handler: fn(&CapabilitiesContext, &messaging::BrokerMessage, &str) -> CallResult,
+) -> CallResult {
+    match rid.parse::<Rid>() {
+        Ok(Rid::Schema(ref component)) => handler(ctx, msg, component),
+        Ok(other) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&format!("not a schema resource: {}", other)),
+        ),
+        Err(e) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&e.to_string()),
+        ),
+    }
+}
+
+const ENTITIES_RID_PREFIX: &str = "decs.entities.";
+
+/// Prefix of the subject on which RESgate sends query requests for a resource, after
+/// it has been told by a query event that query results may have changed
+const QUERY_SUBJECT_PREFIX: &str = "query.";
+const ENTITIES_QUERY_PREFIX: &str = "query.decs.entities.";
+
+/// Parses a shard entities resource ID and hands the shard it refers to to the given handler
+fn with_entities_rid(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &str,
+    handler: fn(&CapabilitiesContext, &messaging::BrokerMessage, &str) -> CallResult,
+) -> CallResult {
+    match rid.parse::<Rid>() {
+        Ok(Rid::Entities(ref shard)) => handler(ctx, msg, shard),
+        Ok(other) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&format!("not an entities resource: {}", other)),
+        ),
+        Err(e) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&e.to_string()),
+        ),
+    }
+}
+
+const PREFAB_RID_PREFIX: &str = "decs.prefabs.";
+
+/// Parses a prefab resource ID and hands the prefab name it refers to to the given handler
+fn with_prefab_rid(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &str,
+    handler: fn(&CapabilitiesContext, &messaging::BrokerMessage, &str) -> CallResult,
+) -> CallResult {
+    match rid.parse::<Rid>() {
+        Ok(Rid::Prefab(ref name)) => handler(ctx, msg, name),
+        Ok(other) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&format!("not a prefab resource: {}", other)),
+        ),
+        Err(e) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(&e.to_string()),
+        ),
+    }
+}
+
+/// Schemas and prefabs may be managed by admins and by server-side systems, whose
+/// requests carry no connection token
+fn may_manage_definitions(msg: &messaging::BrokerMessage) -> bool {
+    AccessToken::from_request(&msg.body).is_none_or(|t| t.is_admin())
+}
+
+fn handle_schema_access(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    component: &str,
+) -> CallResult {
+    let result = match AccessToken::from_request(&msg.body) {
+        Some(ref t) if t.is_admin() => codec::gateway::access_result(false, Some("set,delete")),
+        _ => codec::gateway::error_access_denied(&format!(
+            "Only admins may manage the schema for {}",
+            component
+        )),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Registers the schema for a component. The params of the request are the JSON Schema
+/// itself; see `decscloud_common::schema` for the supported keywords
+fn handle_schema_set(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    component: &str,
+) -> CallResult {
+    if !may_manage_definitions(msg) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may manage schemas"),
+        );
+    }
+    let schema = extract_model_from_set(&msg.body)?;
+    ctx.log(&format!("Registering schema for component {}", component));
+    let result = match codec::schema::check_schema(&schema) {
+        Ok(()) => {
+            store::put_schema(ctx.kv(), component, &schema)?;
+            codec::gateway::success_response()
+        }
+        Err(e) => codec::gateway::error_invalid_params(&e),
+    };
+    reply(ctx, msg, &result)
+}
+
+fn handle_schema_delete(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    component: &str,
+) -> CallResult {
+    if !may_manage_definitions(msg) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may manage schemas"),
+        );
+    }
+    let result = if store::delete_schema(ctx.kv(), component)? {
+        codec::gateway::success_response()
+    } else {
+        codec::gateway::error_not_found(&format!("No schema registered for {}", component))
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Any logged in user may read prefabs, while only admins may manage them
+fn handle_prefab_access(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    name: &str,
+) -> CallResult {
+    let result = match AccessToken::from_request(&msg.body) {
+        Some(ref t) if t.is_admin() => codec::gateway::access_result(true, Some("set,delete")),
+        Some(_) => codec::gateway::access_result(true, None),
+        None => codec::gateway::error_access_denied(&format!(
+            "Reading prefab {} requires a login",
+            name
+        )),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Responds with a prefab as a model whose properties are its components. Component
+/// values are objects, so each is wrapped as a RES data value
+fn handle_prefab_get(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    name: &str,
+) -> CallResult {
+    let result = match store::get_prefab(ctx.kv(), name)? {
+        Some(prefab) => {
+            codec::gateway::model_result(serde_json::Value::Object(prefab_model(&prefab)))
+        }
+        None => codec::gateway::error_not_found(&format!("No such prefab: {}", name)),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Registers a prefab, replacing any previous prefab of the same name. The params carry
+/// its components in the same shape as when spawning an entity with `new`:
+/// ```
+/// {
+///   "params" : { "components": { "hull": { "integrity": 100 }, "cargo_hold": { ... } } },
+///   ...
+/// }
+/// ```
+/// Values are checked against component schemas when an entity is spawned, after overrides
+/// have been applied
+fn handle_prefab_set(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    name: &str,
+) -> CallResult {
+    if !may_manage_definitions(msg) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may manage prefabs"),
+        );
+    }
+    let params = extract_model_from_set(&msg.body)?;
+    let components = match params["components"].as_object() {
+        Some(components) => components,
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("components must be an object"),
+            )
+        }
+    };
+    if let Err(e) = check_bundle(components) {
+        return reply(ctx, msg, &codec::gateway::error_invalid_params(&e));
+    }
+    ctx.log(&format!("Registering prefab {}", name));
+    let mut model = match store::get_prefab(ctx.kv(), name)? {
+        Some(previous) => prefab_model(&previous),
+        None => serde_json::Map::new(),
+    };
+    let mut patch = prefab_model(components);
+    for removed in model.keys().filter(|k| !components.contains_key(*k)) {
+        patch.insert(removed.clone(), serde_json::Value::Null);
+    }
+    let changed = codec::gateway::merge_model(&mut model, &patch);
+    store::put_prefab(ctx.kv(), name, components)?;
+    if !changed.is_empty() {
+        let rid = Rid::Prefab(name.to_string()).to_string();
+        publish_event(
+            ctx,
+            &ResEvent::change(&rid, serde_json::Value::Object(changed)),
+        )?;
+    }
+    reply(ctx, msg, &codec::gateway::success_response())
+}
+
+fn handle_prefab_delete(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    name: &str,
+) -> CallResult {
+    if !may_manage_definitions(msg) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may manage prefabs"),
+        );
+    }
+    let result = if store::delete_prefab(ctx.kv(), name)? {
+        publish_event(
+            ctx,
+            &ResEvent::delete(&Rid::Prefab(name.to_string()).to_string()),
+        )?;
+        codec::gateway::success_response()
+    } else {
+        codec::gateway::error_not_found(&format!("No such prefab: {}", name))
+    };
+    reply(ctx, msg, &result)
+}
+
+/// The RES model of a prefab's components, each wrapped as a data value
+fn prefab_model(
+    components: &serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    components
+        .iter()
+        .map(|(name, value)| (name.clone(), json!({ "data": value })))
+        .collect()
+}
+
+/// Checks a component value against the schema registered for its component, if any.
+/// Returns the error to reply with when the value does not conform
+fn schema_violation(
+    ctx: &CapabilitiesContext,
+    rid: &Rid,
+    value: &serde_json::Value,
+) -> std::result::Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
+    let (_, _, component) = store::component_parts(rid)?;
+    let schema = match store::get_schema(ctx.kv(), component)? {
+        Some(schema) => schema,
+        None => return Ok(None),
+    };
+    Ok(codec::schema::validate(&schema, value).err().map(|errors| {
+        codec::gateway::error_response_with_data(
+            ErrorCode::InvalidParams,
+            &format!("Value does not match the schema for {}", component),
+            json!({ "errors": errors }),
+        )
+    }))
+}
+
+/// The level of access a connection has to the components of an entity
+#[derive(Debug, PartialEq)]
+enum EntityAccess {
+    Full,
+    ReadOnly,
+    Denied,
+}
+
+/// Decides what a connection may do with an entity's components. Admins and the
+/// entity's owner have full access, other users with entities in the same shard may
+/// read, and everyone else is denied. An entity nobody has claimed yet is treated as
+/// owned by someone else, so only an admin may write to it; users get entities of their
+/// own by spawning them
+fn entity_access(
+    token: Option<&AccessToken>,
+    owner: Option<&str>,
+    shard_users: &[String],
+) -> EntityAccess {
+    match token {
+        None => EntityAccess::Denied,
+        Some(t) if t.is_admin() => EntityAccess::Full,
+        Some(t) if owner == Some(t.user_id.as_str()) => EntityAccess::Full,
+        Some(t) if shard_users.contains(&t.user_id) => EntityAccess::ReadOnly,
+        Some(_) => EntityAccess::Denied,
+    }
+}
+
+/// Responds to a RES messaging server with the access metadata, as determined
+/// by the connection token in the request
+fn handle_access(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let token = AccessToken::from_request(&msg.body);
+    let shard = shard_from_rid(rid);
+    if let Rid::ShardComponents(_) = rid {
+        // Any authenticated user may spawn entities, which they then own
+        let result = match token {
+            Some(ref t) if t.is_admin() => codec::gateway::access_result(false, Some("*")),
+            Some(_) => codec::gateway::access_result(false, Some("new,spawn,batch")),
+            None => codec::gateway::error_access_denied("Spawning entities requires a login"),
+        };
+        return reply(ctx, msg, &result);
+    }
+    let entity = rid.entity().unwrap_or_default();
+    let access = match token {
+        Some(ref t) if !t.is_admin() => {
+            let owner = store::entity_owner(ctx.kv(), shard, entity)?;
+            let users = store::shard_users(ctx.kv(), shard)?;
+            entity_access(token.as_ref(), owner.as_deref(), &users)
+        }
+        _ => entity_access(token.as_ref(), None, &[]),
+    };
+    let result = match access {
+        EntityAccess::Full => codec::gateway::access_result(true, Some("*")),
+        EntityAccess::ReadOnly => codec::gateway::access_result(true, None),
+        EntityAccess::Denied => codec::gateway::error_access_denied(&format!(
+            "No access to entity {} in shard {}",
+            entity, shard
+        )),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// The shard's entity listing may be read by admins and by any user owning an entity
+/// in the shard, the same users who may read the entities themselves
+fn handle_entities_access(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+) -> CallResult {
+    let result = match AccessToken::from_request(&msg.body) {
+        Some(ref t) if t.is_admin() => codec::gateway::access_result(true, None),
+        Some(ref t) if store::shard_users(ctx.kv(), shard)?.contains(&t.user_id) => {
+            codec::gateway::access_result(true, None)
+        }
+        _ => codec::gateway::error_access_denied(&format!("No access to shard {}", shard)),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Claims an entity for the user making a write request, so that later access
+/// requests can be checked against its owner. Writes without a connection token
+/// come from server-side systems and claim the entity for the system
+fn claim_entity(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> codec::kv::Result<()> {
+    let owner = AccessToken::from_request(&msg.body)
+        .map(|t| t.user_id)
+        .unwrap_or_else(|| store::SYSTEM_OWNER.to_string());
+    store::claim_entity(
+        ctx.kv(),
+        shard_from_rid(rid),
+        rid.entity().unwrap_or_default(),
+        &owner,
+    )
+}
+
 /// Responds to a RES protocol GET request, which can be for a single model
 /// or a collection (which is an array of rids)
-fn handle_get(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage, rid: &str) -> CallResult {
+fn handle_get(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage, rid: &Rid) -> CallResult {
     ctx.log(&format!(
         "Handling GET request: {}, rid: {}",
         msg.subject, rid
     ));
+    match rid {
+        Rid::Component {
+            shard,
+            entity,
+            component: None,
+            ..
+        } => return handle_entity_get(ctx, msg, shard, entity),
+        Rid::ShardComponents(_) => return reply_not_a_component(ctx, msg, rid),
+        _ => {}
+    }
 
-    if let store::ComponentType::Model = store::component_type(ctx, rid)? {
+    if let store::ComponentType::Model = store::component_type(ctx.kv(), rid)? {
         handle_single_get(ctx, msg, rid)
     } else {
         handle_collection_get(ctx, msg, rid)
@@ -65,13 +600,26 @@ fn handle_get(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage, rid: &s
 fn handle_delete(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    rid: &Rid,
 ) -> CallResult {
     ctx.log(&format!(
         "Handling DELETE request: {}, rid: {}",
         msg.subject, rid
     ));
-    if let store::ComponentType::Model = store::component_type(ctx, rid)? {
+    match rid {
+        Rid::Component {
+            shard,
+            entity,
+            component: None,
+            ..
+        } => return handle_entity_delete(ctx, msg, shard, entity),
+        Rid::ShardComponents(_) => return reply_not_a_component(ctx, msg, rid),
+        _ => {}
+    }
+    if let Some(collection) = store::item_collection(rid) {
+        return handle_item_delete(ctx, msg, &collection, rid);
+    }
+    if let store::ComponentType::Model = store::component_type(ctx.kv(), rid)? {
         handle_model_delete(ctx, msg, rid)
     } else {
         handle_collection_delete_item(ctx, msg, rid)
@@ -81,15 +629,32 @@ fn handle_delete(
 fn handle_model_delete(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    rid: &Rid,
 ) -> CallResult {
-    store::delete_component(ctx, rid)?;
-
-    // TODO: figure out what to publish for RES protocol model deletion
-
-    let tokens: Vec<&str> = rid.split('.').collect();
-    let shard = tokens[2]; // decs.components.(shard).(entity)...
-    publish_update_shard(ctx, shard, -1)?;
+    let params = extract_model_from_set(&msg.body)?;
+    let expected = match expected_revision(&params) {
+        Ok(expected) => expected,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    let counted = store::component_counted(ctx.kv(), rid)?;
+    let entity_index = match store::delete_component(ctx.kv(), rid, expected) {
+        Ok(entity_index) => entity_index,
+        Err(ref e) if e.to_string() == store::REVISION_CONFLICT => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_conflict(store::REVISION_CONFLICT),
+            )
+        }
+        Err(e) => return Err(e),
+    };
+    publish_event(ctx, &ResEvent::delete(&rid.to_string()))?;
+    if let Some(idx) = entity_index {
+        publish_entity_component_remove(ctx, rid, idx)?;
+    }
+    if counted {
+        publish_update_shard(ctx, shard_from_rid(rid), -1)?;
+    }
 
     if !msg.reply_to.is_empty() {
         ctx.msg().publish(
@@ -105,40 +670,256 @@ fn handle_model_delete(
 fn handle_collection_delete_item(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    rid: &Rid,
 ) -> CallResult {
-    let raw: serde_json::Value = serde_json::from_slice(&msg.body)?;
-    let item_rid: String = raw["params"]["rid"].as_str().unwrap().to_string();
-    let idx = store::remove_component_from_collection(ctx, rid, &item_rid)?;
-    publish_collection_remove(ctx, rid, idx)?;
-    if !msg.reply_to.is_empty() {
-        ctx.msg().publish(
-            &msg.reply_to,
-            None,
-            &serde_json::to_vec(&codec::gateway::success_response())?,
-        )?;
+    let params = extract_model_from_set(&msg.body)?;
+    match params["rid"].as_str() {
+        Some(item_rid) => remove_collection_item(ctx, msg, rid, item_rid, &params),
+        None => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_invalid_params(
+                "Removing from a collection needs the rid of the item",
+            ),
+        ),
+    }
+}
+
+/// Handles a delete call on a collection item itself, which removes it from its collection
+fn handle_item_delete(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    collection: &Rid,
+    item: &Rid,
+) -> CallResult {
+    let params = extract_model_from_set(&msg.body)?;
+    remove_collection_item(ctx, msg, collection, &item.to_string(), &params)
+}
+
+/// Removes an item from a collection, publishing the remove event with the index the item
+/// occupied. Replies not found if the collection doesn't have the item
+fn remove_collection_item(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+    item_rid: &str,
+    params: &serde_json::Value,
+) -> CallResult {
+    let expected = match expected_revision(params) {
+        Ok(expected) => expected,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    let idx = match store::remove_component_from_collection(ctx.kv(), rid, item_rid, expected) {
+        Ok(idx) => idx,
+        Err(ref e)
+            if e.to_string() == store::REVISION_CONFLICT
+                || e.to_string() == store::COLLECTION_BUSY =>
+        {
+            return reply(ctx, msg, &codec::gateway::error_conflict(&e.to_string()))
+        }
+        Err(ref e) if e.to_string() == store::NO_SUCH_ITEM => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_not_found(&format!("No such item in {}: {}", rid, item_rid)),
+            )
+        }
+        Err(e) => return Err(e),
     };
+    publish_collection_remove(ctx, rid, idx)?;
+    publish_event(ctx, &ResEvent::delete(item_rid))?;
+    reply(ctx, msg, &codec::gateway::success_response())
+}
 
-    Ok(vec![])
+/// Responds to a RES protocol GET request for an entity with the collection of its
+/// components, models and collections alike
+fn handle_entity_get(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+    entity: &str,
+) -> CallResult {
+    if !store::entity_exists(ctx.kv(), shard, entity)? {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_not_found(&format!("No such entity: {}", entity)),
+        );
+    }
+    let components = store::entity_components(ctx.kv(), shard, entity)?;
+    let result = codec::gateway::collection_result(
+        components
+            .iter()
+            .map(|name| Rid::component(shard, entity, name)),
+    );
+    reply(ctx, msg, &result)
 }
 
-/// Responds to a RES protocol GET request with a collection of reference IDs
-fn handle_collection_get(
+/// Responds to a RES protocol GET request for the entities of a shard. Without a query
+/// this is the first page of all of the shard's entities. The query string is read from
+/// the subject or, as RESgate sends it, from the `query` field of the request
+fn handle_entities_get(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    shard: &str,
+) -> CallResult {
+    let query = match ResProtocolRequest::from(msg.subject.as_str()) {
+        ResProtocolRequest::Query(_, query) => Some(query),
+        _ => request_query(&msg.body)?,
+    };
+    let result = match query {
+        None => codec::gateway::collection_result(query_entity_rids(
+            ctx,
+            shard,
+            &EntityQuery::default(),
+        )?),
+        Some(query) => match query.parse::<EntityQuery>() {
+            Ok(q) => codec::gateway::query_collection_result(
+                query_entity_rids(ctx, shard, &q)?,
+                &q.to_string(),
+            ),
+            Err(e) => codec::gateway::error_invalid_query(&e),
+        },
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Answers a query request from RESgate with the current results of one of the queries
+/// it holds on a shard's entities
+fn handle_entities_query(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
 ) -> CallResult {
-    let rids = store::get_collection_rids(ctx, &rid)?;
+    let query = request_query(&msg.body)?.unwrap_or_default();
+    let result = match query.parse::<EntityQuery>() {
+        Ok(q) => codec::gateway::collection_result(query_entity_rids(ctx, shard, &q)?),
+        Err(e) => codec::gateway::error_invalid_query(&e),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// The query string carried in the body of a RES request, if there is one
+fn request_query(body: &[u8]) -> Result<Option<String>> {
+    let v: serde_json::Value = serde_json::from_slice(body)?;
+    Ok(v["query"]
+        .as_str()
+        .filter(|q| !q.is_empty())
+        .map(|q| q.to_string()))
+}
 
-    let refs: Vec<_> = rids
+/// Runs a query against a shard, returning the page of matching entities as resource IDs
+fn query_entity_rids(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    query: &EntityQuery,
+) -> codec::kv::Result<Vec<String>> {
+    let entities = store::query_entities(ctx.kv(), shard, &query.with, &query.without)?;
+    Ok(entities
         .iter()
-        .map(|s| codec::gateway::ResourceIdentifier { rid: s.clone() })
-        .collect();
-    let result = json!({
-        "result": {
-            "collection": refs
+        .skip(query.offset)
+        .take(query.limit)
+        .map(|entity| format!("decs.components.{}.{}", shard, entity))
+        .collect())
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 25;
+const MAX_QUERY_LIMIT: usize = 100;
+
+/// A query on the entities of a shard, e.g. `with=position,velocity&without=dead&limit=10`.
+/// An entity matches if it has every `with` component and none of the `without` ones.
+/// Matches are ordered by entity ID and paged with `offset` and `limit`
+#[derive(Debug, Clone, PartialEq)]
+struct EntityQuery {
+    with: Vec<String>,
+    without: Vec<String>,
+    offset: usize,
+    limit: usize,
+}
+
+impl Default for EntityQuery {
+    fn default() -> Self {
+        EntityQuery {
+            with: vec![],
+            without: vec![],
+            offset: 0,
+            limit: DEFAULT_QUERY_LIMIT,
         }
-    });
+    }
+}
+
+impl std::str::FromStr for EntityQuery {
+    type Err = String;
+
+    fn from_str(source: &str) -> std::result::Result<Self, Self::Err> {
+        let mut query = EntityQuery::default();
+        for pair in source.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+                None => (pair, ""),
+            };
+            match key {
+                "with" => query.with.extend(component_names(value)?),
+                "without" => query.without.extend(component_names(value)?),
+                "offset" => {
+                    query.offset = value
+                        .parse()
+                        .map_err(|_| format!("offset must be a non-negative integer: {}", value))?
+                }
+                "limit" => match value.parse() {
+                    Ok(limit) if limit > 0 && limit <= MAX_QUERY_LIMIT => query.limit = limit,
+                    _ => {
+                        return Err(format!(
+                            "limit must be between 1 and {}: {}",
+                            MAX_QUERY_LIMIT, value
+                        ))
+                    }
+                },
+                other => return Err(format!("unknown query parameter: {}", other)),
+            }
+        }
+        for names in [&mut query.with, &mut query.without] {
+            names.sort();
+            names.dedup();
+        }
+        Ok(query)
+    }
+}
+
+/// The normalized form of the query, under which RESgate caches its results
+impl std::fmt::Display for EntityQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.with.is_empty() {
+            write!(f, "with={}&", self.with.join(","))?;
+        }
+        if !self.without.is_empty() {
+            write!(f, "without={}&", self.without.join(","))?;
+        }
+        write!(f, "offset={}&limit={}", self.offset, self.limit)
+    }
+}
+
+/// Splits a comma-separated list of component names
+fn component_names(list: &str) -> std::result::Result<Vec<String>, String> {
+    list.split(',')
+        .map(|name| {
+            if valid_component_name(name) {
+                Ok(name.to_string())
+            } else {
+                Err(format!("invalid component name: '{}'", name))
+            }
+        })
+        .collect()
+}
+
+/// Responds to a RES protocol GET request with a collection of reference IDs
+fn handle_collection_get(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let rids = store::get_collection_rids(ctx.kv(), rid)?;
+    let result = codec::gateway::collection_result(rids);
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
@@ -147,11 +928,15 @@ fn handle_collection_get(
 fn handle_single_get(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    rid: &Rid,
 ) -> CallResult {
-    match store::get_component(ctx, rid) {
+    match store::get_component(ctx.kv(), rid) {
         Ok(c) => {
-            let model_json: serde_json::Value = serde_json::from_str(&c)?;
+            let mut model_json: serde_json::Value = serde_json::from_str(&c)?;
+            if let Some(model) = model_json.as_object_mut() {
+                let rev = store::component_revision(ctx.kv(), rid)?;
+                model.insert(REVISION_PROPERTY.to_string(), json!(rev));
+            }
             ctx.msg().publish(
                 &msg.reply_to,
                 None,
@@ -174,64 +959,1226 @@ fn handle_single_get(
     }
 }
 
-fn handle_collection_new(
+/// Handles a `new` call, which adds an item to a collection component or, on
+/// `decs.components.{shard}`, spawns a new entity
+fn handle_new(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage, rid: &Rid) -> CallResult {
+    match rid {
+        Rid::ShardComponents(shard) => handle_entity_new(ctx, msg, shard),
+        _ if is_component(rid) => handle_collection_new(ctx, msg, rid),
+        _ => reply_not_a_component(ctx, msg, rid),
+    }
+}
+
+/// Spawns an entity with a server-generated ID. The payload carries its initial components:
+/// ```
+/// {
+///   "params" : { "components": { "position": { ... }, "velocity": { ... } } },
+///   ...
+/// }
+/// ```
+/// Replies with the resource ID of the new entity
+fn handle_entity_new(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    shard: &str,
 ) -> CallResult {
-    let new_component = extract_model_from_set(&msg.body)?;
-    ctx.log(&format!(
-        "Handling collection new: {}, rid: {}",
-        msg.subject, rid
-    ));
-    let (new_index, item_rid) =
-        store::add_component_to_collection(ctx, rid, &serde_json::to_string(&new_component)?)?;
-    publish_collection_add(ctx, &rid, &item_rid, new_index)?;
-    let resid = codec::gateway::ResourceIdentifier {
-        rid: item_rid.clone(),
-    };
-    let result = json!({ "result": resid });
-    if !msg.reply_to.is_empty() {
-        ctx.msg()
-            .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
+    let params = extract_model_from_set(&msg.body)?;
+    let empty = serde_json::Map::new();
+    let bundle = match &params["components"] {
+        serde_json::Value::Null => &empty,
+        serde_json::Value::Object(bundle) => bundle,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("components must be an object"),
+            )
+        }
     };
-    Ok(vec![])
+    spawn_entity(ctx, msg, shard, bundle)
 }
 
-/// When the RES protocol invokes a set for a single model, the payload looks as follows:
+/// Spawns an entity from a prefab. The payload names the prefab and, optionally, overrides
+/// for its component values:
 /// ```
 /// {
-///   "params" : ... the model object ..,
-///   "token" : .. access token ...,
-///   "cid" : ... connection id ...
+///   "params" : { "prefab": "freighter", "overrides": { "position": { "x": 10 }, "cargo": null } },
+///   ...
 /// }
 /// ```
-fn handle_model_set(
+/// An override is merged into the prefab's value for the component like the params of a
+/// `set`, or adds the component if the prefab doesn't have it. A `null` override leaves the
+/// component out. Replies with the resource ID of the new entity
+fn handle_spawn(
     ctx: &CapabilitiesContext,
     msg: &messaging::BrokerMessage,
-    rid: &str,
+    rid: &Rid,
 ) -> CallResult {
-    let comp = extract_model_from_set(&msg.body)?;
-    ctx.log(&format!(
-        "Handling set request: {}, reply-to: {}",
-        msg.subject, msg.reply_to
+    let shard = match rid {
+        Rid::ShardComponents(shard) => shard,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_method_not_found("spawn is only available on a shard"),
+            )
+        }
+    };
+    let params = extract_model_from_set(&msg.body)?;
+    let name = match params["prefab"].as_str() {
+        Some(name) => name,
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("prefab must be a string"),
+            )
+        }
+    };
+    let prefab = match store::get_prefab(ctx.kv(), name)? {
+        Some(prefab) => prefab,
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_not_found(&format!("No such prefab: {}", name)),
+            )
+        }
+    };
+    let empty = serde_json::Map::new();
+    let overrides = match &params["overrides"] {
+        serde_json::Value::Null => &empty,
+        serde_json::Value::Object(overrides) => overrides,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("overrides must be an object"),
+            )
+        }
+    };
+    match apply_overrides(prefab, overrides) {
+        Ok(bundle) => spawn_entity(ctx, msg, shard, &bundle),
+        Err(e) => reply(ctx, msg, &codec::gateway::error_invalid_params(&e)),
+    }
+}
+
+/// Applies spawn overrides to the component values of a prefab, yielding the bundle of
+/// components to spawn with
+fn apply_overrides(
+    mut bundle: serde_json::Map<String, serde_json::Value>,
+    overrides: &serde_json::Map<String, serde_json::Value>,
+) -> std::result::Result<serde_json::Map<String, serde_json::Value>, String> {
+    for (name, value) in overrides {
+        match value {
+            serde_json::Value::Null => {
+                bundle.remove(name);
+            }
+            serde_json::Value::Object(patch) => {
+                let entry = bundle.entry(name.clone()).or_insert_with(|| json!({}));
+                match entry.as_object_mut() {
+                    Some(model) => {
+                        codec::gateway::merge_model(model, patch);
+                    }
+                    None => *entry = value.clone(),
+                }
+            }
+            _ => return Err(format!("override for {} must be an object or null", name)),
+        }
+    }
+    Ok(bundle)
+}
+
+/// Spawns an entity with the given bundle of components. Every component is checked
+/// before any is written, so a bundle with one bad component creates nothing
+fn spawn_entity(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+    bundle: &serde_json::Map<String, serde_json::Value>,
+) -> CallResult {
+    if let Err(e) = check_bundle(bundle) {
+        return reply(ctx, msg, &codec::gateway::error_invalid_params(&e));
+    }
+    // checked up front, as an empty bundle reserves nothing from the shard manager
+    if let Some(err) = closed_to_entities_error(ctx.kv(), shard)? {
+        return reply(ctx, msg, &err);
+    }
+    let entity = store::next_entity_id(ctx.kv())?;
+    ctx.log(&format!("Spawning entity {} in shard {}", entity, shard));
+
+    let mut components = vec![];
+    for (name, value) in bundle {
+        let rid = Rid::component(shard, &entity, name);
+        if let Some(err) = schema_violation(ctx, &rid, value)? {
+            return reply(ctx, msg, &err);
+        }
+        components.push((rid, value));
+    }
+
+    if !components.is_empty() {
+        if let Err(err) = reserve_shard_capacity(ctx, shard, components.len() as u32)? {
+            return reply(ctx, msg, &err);
+        }
+    }
+    for (rid, value) in &components {
+        store::put_component(ctx.kv(), rid, &serde_json::to_string(value)?, None, None)?;
+    }
+    let entity_rid = Rid::Component {
+        shard: shard.to_string(),
+        entity,
+        component: None,
+        item: None,
+    };
+    claim_entity(ctx, msg, &entity_rid)?;
+    publish_entities_query(ctx, shard)?;
+    reply(
+        ctx,
+        msg,
+        &codec::gateway::resource_result(&entity_rid.to_string()),
+    )
+}
+
+/// Checks that a bundle maps valid component names to component values, which are objects
+fn check_bundle(
+    bundle: &serde_json::Map<String, serde_json::Value>,
+) -> std::result::Result<(), String> {
+    for (name, value) in bundle {
+        if !valid_component_name(name) {
+            return Err(format!("invalid component name: {}", name));
+        }
+        if !value.is_object() {
+            return Err(format!("component {} must be an object", name));
+        }
+    }
+    Ok(())
+}
+
+/// Shard names must be usable as a single resource ID segment
+fn valid_shard_name(name: &str) -> bool {
+    matches!(
+        Rid::ShardComponents(name.to_string())
+            .to_string()
+            .parse::<Rid>(),
+        Ok(Rid::ShardComponents(_))
+    )
+}
+
+/// Component names must be usable as a single resource ID segment
+fn valid_component_name(name: &str) -> bool {
+    matches!(
+        format!("{}{}", SCHEMA_RID_PREFIX, name).parse::<Rid>(),
+        Ok(Rid::Schema(_))
+    )
+}
+
+/// Destroys an entity along with all of its components, publishing a delete event for
+/// every resource that goes away
+fn handle_entity_delete(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+    entity: &str,
+) -> CallResult {
+    ctx.log(&format!("Destroying entity {} in shard {}", entity, shard));
+    let removal = match store::delete_entity(ctx.kv(), shard, entity)? {
+        Some(removal) => removal,
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_not_found(&format!("No such entity: {}", entity)),
+            )
+        }
+    };
+    for rid in &removal.deleted {
+        publish_event(ctx, &ResEvent::delete(rid))?;
+    }
+    if removal.count > 0 {
+        publish_update_shard(ctx, shard, -removal.count)?;
+    }
+    publish_entities_query(ctx, shard)?;
+    reply(ctx, msg, &codec::gateway::success_response())
+}
+
+/// Moves an entity to another shard, e.g. as a player crosses from one zone into the next.
+/// The params name the `shard` to move to. The entity keeps its ID and its components keep
+/// their names, so they reappear under the new shard's resource IDs, while clients of the
+/// old ones see them deleted. Room for the components is reserved in the new shard before
+/// anything moves. Replies with the entity's resource ID in the new shard
+fn handle_entity_migrate(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let (shard, entity) = match rid {
+        Rid::Component {
+            shard,
+            entity,
+            component: None,
+            ..
+        } => (shard.as_str(), entity.as_str()),
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params(&format!("not an entity: {}", rid)),
+            )
+        }
+    };
+    let params = extract_model_from_set(&msg.body)?;
+    let to = match params["shard"].as_str() {
+        Some(to) if to == shard => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params(&format!(
+                    "Entity {} is already in shard {}",
+                    entity, shard
+                )),
+            )
+        }
+        Some(to) if valid_shard_name(to) => to,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params(
+                    "Migrating an entity needs the name of the shard to move it to",
+                ),
+            )
+        }
+    };
+    if let Some(err) = closed_to_entities_error(ctx.kv(), to)? {
+        return reply(ctx, msg, &err);
+    }
+    ctx.log(&format!(
+        "Migrating entity {} from shard {} to shard {}",
+        entity, shard, to
     ));
-    let existed = store::put_component(ctx, rid, &serde_json::to_string(&comp)?)?;
-    if !existed {
-        let shard = shard_from_rid(rid);
-        publish_update_shard(ctx, &shard, 1)?;
+
+    let count = store::entity_value_count(ctx.kv(), shard, entity)?;
+    if count > 0 {
+        if let Err(err) = reserve_shard_capacity(ctx, to, count as u32)? {
+            return reply(ctx, msg, &err);
+        }
+    }
+    let migration = match store::migrate_entity(ctx.kv(), shard, entity, to) {
+        Ok(Some(migration)) => migration,
+        failed => {
+            if count > 0 {
+                publish_update_shard(ctx, to, -count)?;
+            }
+            return match failed {
+                Err(ref e) if e.to_string() == store::ENTITY_EXISTS => reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_conflict(&format!(
+                        "Shard {} already has an entity {}",
+                        to, entity
+                    )),
+                ),
+                Err(e) => Err(e),
+                Ok(_) => reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_not_found(&format!("No such entity: {}", entity)),
+                ),
+            };
+        }
+    };
+    // values that lapsed while the entity was moving were reserved but not added
+    if migration.count < count {
+        publish_update_shard(ctx, to, migration.count - count)?;
     }
-    publish_model_change(ctx, comp, rid)?;
+    for rid in &migration.removal.deleted {
+        publish_event(ctx, &ResEvent::delete(rid))?;
+    }
+    if migration.removal.count > 0 {
+        publish_update_shard(ctx, shard, -migration.removal.count)?;
+    }
+    publish_entities_query(ctx, shard)?;
+    publish_entities_query(ctx, to)?;
+    let entity_rid = Rid::Component {
+        shard: to.to_string(),
+        entity: entity.to_string(),
+        component: None,
+        item: None,
+    };
+    reply(
+        ctx,
+        msg,
+        &codec::gateway::resource_result(&entity_rid.to_string()),
+    )
+}
+
+/// Adds an item to a collection component. The params are the new item, and may include
+/// a `ttl` in seconds after which the item lapses and is removed from the collection
+fn handle_collection_new(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let params = extract_model_from_set(&msg.body)?;
+    ctx.log(&format!(
+        "Handling collection new: {}, rid: {}",
+        msg.subject, rid
+    ));
+    let plan = match plan_new(ctx, rid, &params)? {
+        Ok(plan) => plan,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    if let Err(err) = reserve_shard_capacity(ctx, shard_from_rid(rid), 1)? {
+        return reply(ctx, msg, &err);
+    }
+    let added = match store::add_component_to_collection(
+        ctx.kv(),
+        rid,
+        &serde_json::to_string(&plan.item)?,
+        plan.ttl,
+    ) {
+        Ok(added) => added,
+        Err(e) => {
+            publish_update_shard(ctx, shard_from_rid(rid), -1)?;
+            if e.to_string() == store::COLLECTION_BUSY {
+                return reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_conflict(store::COLLECTION_BUSY),
+                );
+            }
+            return Err(e);
+        }
+    };
+    claim_entity(ctx, msg, rid)?;
+    publish_collection_add(ctx, rid, &added.item_rid, added.index)?;
+    if let Some(idx) = added.entity_index {
+        publish_entity_component_add(ctx, rid, idx)?;
+    }
+    let result = codec::gateway::resource_result(&added.item_rid);
     if !msg.reply_to.is_empty() {
-        ctx.msg().publish(
-            &msg.reply_to,
-            None,
-            &serde_json::to_vec(&codec::gateway::success_response())?,
-        )?;
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
+    };
+    Ok(vec![])
+}
+
+/// Adds an item to a collection component at a given position. The params are those of
+/// a `new`, plus the `idx` the item is to occupy; an `idx` of the collection's length
+/// appends the item
+fn handle_collection_insert(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let mut params = extract_model_from_set(&msg.body)?;
+    let idx = match index_param(&params) {
+        Ok(idx) => idx,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    if let Some(params) = params.as_object_mut() {
+        params.remove(INDEX_PARAM);
+    }
+    let plan = match plan_new(ctx, rid, &params)? {
+        Ok(plan) => plan,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    if let Err(err) = reserve_shard_capacity(ctx, shard_from_rid(rid), 1)? {
+        return reply(ctx, msg, &err);
+    }
+    let added = match store::insert_component_into_collection(
+        ctx.kv(),
+        rid,
+        &serde_json::to_string(&plan.item)?,
+        idx,
+        plan.ttl,
+    ) {
+        Ok(added) => added,
+        Err(e) => {
+            publish_update_shard(ctx, shard_from_rid(rid), -1)?;
+            if e.to_string() == store::INDEX_OUT_OF_RANGE {
+                return reply(ctx, msg, &error_index_out_of_range(rid, idx));
+            }
+            if e.to_string() == store::COLLECTION_BUSY {
+                return reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_conflict(store::COLLECTION_BUSY),
+                );
+            }
+            return Err(e);
+        }
+    };
+    claim_entity(ctx, msg, rid)?;
+    publish_collection_add(ctx, rid, &added.item_rid, added.index)?;
+    if let Some(idx) = added.entity_index {
+        publish_entity_component_add(ctx, rid, idx)?;
+    }
+    reply(ctx, msg, &codec::gateway::resource_result(&added.item_rid))
+}
+
+/// Moves an item within a collection component. The params are the `rid` of the item and
+/// the `idx` it is to end up at. Clients see the item removed from its old index and added
+/// at the new one
+fn handle_collection_move(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    if !is_component(rid) {
+        return reply_not_a_component(ctx, msg, rid);
+    }
+    let params = extract_model_from_set(&msg.body)?;
+    let item_rid = match params["rid"].as_str() {
+        Some(item_rid) => item_rid,
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params(
+                    "Moving within a collection needs the rid of the item",
+                ),
+            )
+        }
+    };
+    let idx = match index_param(&params) {
+        Ok(idx) => idx,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+    let from = match store::move_collection_item(ctx.kv(), rid, item_rid, idx) {
+        Ok(from) => from,
+        Err(ref e) if e.to_string() == store::NO_SUCH_ITEM => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_not_found(&format!("No such item in {}: {}", rid, item_rid)),
+            )
+        }
+        Err(ref e) if e.to_string() == store::INDEX_OUT_OF_RANGE => {
+            return reply(ctx, msg, &error_index_out_of_range(rid, idx))
+        }
+        Err(ref e) if e.to_string() == store::COLLECTION_BUSY => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_conflict(store::COLLECTION_BUSY),
+            )
+        }
+        Err(e) => return Err(e),
+    };
+    if from != idx {
+        publish_event(ctx, &ResEvent::remove(&rid.to_string(), from))?;
+        publish_event(ctx, &ResEvent::add(&rid.to_string(), item_rid, idx))?;
+    }
+    reply(ctx, msg, &codec::gateway::success_response())
+}
+
+const INDEX_PARAM: &str = "idx";
+
+/// Reads the required collection index from request params, returning the error to reply
+/// with if it is missing or not a non-negative integer
+fn index_param(params: &serde_json::Value) -> std::result::Result<usize, serde_json::Value> {
+    params[INDEX_PARAM]
+        .as_u64()
+        .map(|idx| idx as usize)
+        .ok_or_else(|| codec::gateway::error_invalid_params("idx must be a non-negative integer"))
+}
+
+fn error_index_out_of_range(rid: &Rid, idx: usize) -> serde_json::Value {
+    codec::gateway::error_invalid_params(&format!("Index {} is out of range for {}", idx, rid))
+}
+
+/// A `new` on a collection, checked but not yet written
+struct PlannedNew {
+    /// The new item, without the `ttl` param
+    item: serde_json::Value,
+    ttl: Option<u32>,
+}
+
+/// Works out the item a `new` call adds to a collection, checking it against the
+/// component's schema
+fn plan_new(
+    ctx: &CapabilitiesContext,
+    rid: &Rid,
+    params: &serde_json::Value,
+) -> Checked<PlannedNew> {
+    if !is_component(rid) {
+        return Ok(Err(not_a_component(rid)));
+    }
+    let ttl = match ttl_param(params) {
+        Ok(ttl) => ttl,
+        Err(err) => return Ok(Err(err)),
+    };
+    let mut item = params.clone();
+    if let Some(item) = item.as_object_mut() {
+        item.remove(TTL_PARAM);
+    }
+    if let Some(err) = schema_violation(ctx, rid, &item)? {
+        return Ok(Err(err));
+    }
+    Ok(Ok(PlannedNew { item, ttl }))
+}
+
+/// When the RES protocol invokes a set for a single model, the payload looks as follows:
+/// ```
+/// {
+///   "params" : ... the properties to change ..,
+///   "token" : .. access token ...,
+///   "cid" : ... connection id ...
+/// }
+/// ```
+/// The params are merged into the stored model, a `null` value deleting the property,
+/// and the change event carries only the properties that actually changed. If the params
+/// include `expectedRevision`, the set only succeeds if the component is still at that
+/// revision. With a `ttl` in seconds, the component lapses unless set again in time; a set
+/// that changes the component without one makes it permanent. The component's new
+/// revision is returned
+fn handle_model_set(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let params = extract_model_from_set(&msg.body)?;
+    ctx.log(&format!(
+        "Handling set request: {}, reply-to: {}",
+        msg.subject, msg.reply_to
+    ));
+    let plan = match plan_set(ctx, rid, &params)? {
+        Ok(plan) => plan,
+        Err(err) => return reply(ctx, msg, &err),
+    };
+
+    if !plan.counted {
+        if let Err(err) = reserve_shard_capacity(ctx, shard_from_rid(rid), 1)? {
+            return reply(ctx, msg, &err);
+        }
+    }
+    let rev = if plan.writes() {
+        let write = match store::put_component(
+            ctx.kv(),
+            rid,
+            &serde_json::to_string(&plan.model)?,
+            plan.expected,
+            plan.ttl,
+        ) {
+            Ok(write) => write,
+            Err(e) => {
+                if !plan.counted {
+                    publish_update_shard(ctx, shard_from_rid(rid), -1)?;
+                }
+                if e.to_string() == store::REVISION_CONFLICT {
+                    return reply(
+                        ctx,
+                        msg,
+                        &codec::gateway::error_conflict(store::REVISION_CONFLICT),
+                    );
+                }
+                return Err(e);
+            }
+        };
+        claim_entity(ctx, msg, rid)?;
+        if let Some(idx) = write.entity_index {
+            publish_entity_component_add(ctx, rid, idx)?;
+        }
+        write.revision
+    } else {
+        let rev = store::component_revision(ctx.kv(), rid)?;
+        if plan.expected.is_some_and(|expected| expected != rev) {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_conflict(store::REVISION_CONFLICT),
+            );
+        }
+        rev
+    };
+    if !plan.changed.is_empty() {
+        publish_model_change(ctx, plan.changed_values(rev), rid)?;
+    }
+    reply(
+        ctx,
+        msg,
+        &codec::gateway::call_result(json!({ REVISION_PROPERTY: rev })),
+    )
+}
+
+/// The outcome of checking a request: what to do, or the error to reply with
+type Checked<T> =
+    std::result::Result<std::result::Result<T, serde_json::Value>, Box<dyn std::error::Error>>;
+
+/// A `set` on a model, checked but not yet written
+struct PlannedSet {
+    /// The model as it is to be stored
+    model: serde_json::Value,
+    /// The properties the set changes, as they go in the change event
+    changed: serde_json::Map<String, serde_json::Value>,
+    existed: bool,
+    /// Whether the component already counts towards its shard, which it does from its first
+    /// write until it is deleted or swept up after its time to live has passed
+    counted: bool,
+    expected: Option<i32>,
+    ttl: Option<u32>,
+}
+
+impl PlannedSet {
+    /// A set that changes nothing on an existing component writes nothing, unless it
+    /// renews the component's time to live
+    fn writes(&self) -> bool {
+        !self.existed || !self.changed.is_empty() || self.ttl.is_some()
+    }
+
+    /// The values for the change event, once the set has taken the component to revision `rev`
+    fn changed_values(&self, rev: i32) -> serde_json::Value {
+        let mut changed = self.changed.clone();
+        changed.insert(REVISION_PROPERTY.to_string(), json!(rev));
+        serde_json::Value::Object(changed)
+    }
+}
+
+/// Works out the result of merging set params into a model, checking it against the
+/// component's schema
+fn plan_set(
+    ctx: &CapabilitiesContext,
+    rid: &Rid,
+    params: &serde_json::Value,
+) -> Checked<PlannedSet> {
+    if !is_component(rid) {
+        return Ok(Err(not_a_component(rid)));
+    }
+    if let store::ComponentType::Collection = store::component_type(ctx.kv(), rid)? {
+        return Ok(Err(codec::gateway::error_invalid_params(&format!(
+            "{} is a collection, its items are added with new",
+            rid
+        ))));
+    }
+    let expected = match expected_revision(params) {
+        Ok(expected) => expected,
+        Err(err) => return Ok(Err(err)),
+    };
+    let ttl = match ttl_param(params) {
+        Ok(ttl) => ttl,
+        Err(err) => return Ok(Err(err)),
+    };
+    let mut patch = match params.as_object() {
+        Some(patch) => patch.clone(),
+        None => {
+            return Ok(Err(codec::gateway::error_invalid_params(
+                "Set params must be an object",
+            )))
+        }
+    };
+    let (mut model, existed) = match store::get_component(ctx.kv(), rid) {
+        Ok(c) => match serde_json::from_str(&c)? {
+            serde_json::Value::Object(model) => (model, true),
+            _ => (serde_json::Map::new(), true),
+        },
+        Err(ref e) if e.to_string() == store::NO_SUCH_COMPONENT => (serde_json::Map::new(), false),
+        Err(e) => return Err(e),
+    };
+    let counted = existed || store::component_counted(ctx.kv(), rid)?;
+    patch.remove(REVISION_PROPERTY);
+    patch.remove(EXPECTED_REVISION_PARAM);
+    patch.remove(TTL_PARAM);
+    let changed = codec::gateway::merge_model(&mut model, &patch);
+    let model = serde_json::Value::Object(model);
+    if let Some(err) = schema_violation(ctx, rid, &model)? {
+        return Ok(Err(err));
+    }
+    Ok(Ok(PlannedSet {
+        model,
+        changed,
+        existed,
+        counted,
+        expected,
+        ttl,
+    }))
+}
+
+const MAX_BATCH_OPERATIONS: usize = 100;
+
+/// One operation of a batch, checked but not yet applied
+enum BatchOp {
+    Set(Rid, PlannedSet),
+    New(Rid, PlannedNew),
+    /// Deletes a model component
+    Delete {
+        rid: Rid,
+        expected: Option<i32>,
+    },
+    /// Removes an item from a collection component
+    Remove {
+        rid: Rid,
+        item: Rid,
+        expected: Option<i32>,
+    },
+}
+
+impl BatchOp {
+    /// The component the operation is on
+    fn rid(&self) -> &Rid {
+        match self {
+            BatchOp::Set(rid, _)
+            | BatchOp::New(rid, _)
+            | BatchOp::Delete { rid, .. }
+            | BatchOp::Remove { rid, .. } => rid,
+        }
+    }
+
+    /// The component or item the operation writes to, where it may only appear once in a
+    /// batch. Any number of items may be added to a collection
+    fn target(&self) -> Option<&Rid> {
+        match self {
+            BatchOp::Set(rid, _) | BatchOp::Delete { rid, .. } => Some(rid),
+            BatchOp::Remove { item, .. } => Some(item),
+            BatchOp::New(..) => None,
+        }
+    }
+
+    /// How the operation changes the number of components in the shard
+    fn count(&self) -> i32 {
+        match self {
+            BatchOp::Set(_, plan) if plan.counted => 0,
+            BatchOp::Set(..) | BatchOp::New(..) => 1,
+            BatchOp::Delete { .. } | BatchOp::Remove { .. } => -1,
+        }
+    }
+
+    /// The component to check before anything in the batch is written, along with the
+    /// revision it is expected to be at
+    fn expected(&self) -> Option<(&Rid, i32)> {
+        match self {
+            BatchOp::Set(rid, plan) => plan.expected.map(|expected| (rid, expected)),
+            BatchOp::Delete {
+                rid,
+                expected: Some(expected),
+            } => Some((rid, *expected)),
+            BatchOp::Remove {
+                item,
+                expected: Some(expected),
+                ..
+            } => Some((item, *expected)),
+            _ => None,
+        }
+    }
+}
+
+/// Applies several writes to the components of a shard as one unit. The payload lists the
+/// operations, each a `set`, `new` or `delete` on a component with the params it would
+/// have as a call of its own:
+/// ```
+/// {
+///   "params" : { "operations": [
+///       { "op": "set", "rid": "decs.components.{shard}.{entity}.position", "params": { ... } },
+///       { "op": "delete", "rid": "decs.components.{shard}.{entity}.cargo", "params": { "rid": ... } }
+///   ] },
+///   ...
+/// }
+/// ```
+/// Every operation is checked, including access, schemas and expected revisions, before
+/// anything is written, and if one fails the whole batch is rejected with its error. So is
+/// a batch that would take the shard past its capacity. The events describing the writes
+/// are only published once all of them have been made. The key-value store has no
+/// transactions, so a failure of the store itself part way through cannot be undone.
+/// Replies with a result for each operation, in order
+fn handle_batch(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    let shard = match rid {
+        Rid::ShardComponents(shard) => shard,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_method_not_found("batch is only available on a shard"),
+            )
+        }
+    };
+    let params = extract_model_from_set(&msg.body)?;
+    let operations = match params["operations"].as_array() {
+        Some(ops) if ops.len() <= MAX_BATCH_OPERATIONS => ops,
+        Some(_) => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params(&format!(
+                    "A batch may have at most {} operations",
+                    MAX_BATCH_OPERATIONS
+                )),
+            )
+        }
+        None => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("operations must be an array"),
+            )
+        }
+    };
+
+    let mut ops = vec![];
+    let mut targets = vec![];
+    for (i, operation) in operations.iter().enumerate() {
+        let op = match plan_batch_op(ctx, shard, operation)? {
+            Ok(op) => op,
+            Err(mut err) => {
+                if let Some(message) = err["error"]["message"].as_str() {
+                    err["error"]["message"] = json!(format!("Operation {}: {}", i, message));
+                }
+                return reply(ctx, msg, &err);
+            }
+        };
+        if let Some(target) = op.target() {
+            if targets.contains(target) {
+                return reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_invalid_params(&format!(
+                        "Operation {}: {} is written more than once in the batch",
+                        i, target
+                    )),
+                );
+            }
+            targets.push(target.clone());
+        }
+        ops.push(op);
+    }
+
+    if let Some(token) = AccessToken::from_request(&msg.body) {
+        let users = store::shard_users(ctx.kv(), shard)?;
+        let mut entities: Vec<&str> = ops.iter().filter_map(|op| op.rid().entity()).collect();
+        entities.sort();
+        entities.dedup();
+        for entity in entities {
+            let owner = store::entity_owner(ctx.kv(), shard, entity)?;
+            if entity_access(Some(&token), owner.as_deref(), &users) != EntityAccess::Full {
+                return reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_access_denied(&format!(
+                        "No write access to entity {} in shard {}",
+                        entity, shard
+                    )),
+                );
+            }
+        }
+    }
+
+    // Check every expected revision up front, so that a conflict stops the batch before any
+    // write
+    for (i, op) in ops.iter().enumerate() {
+        let (rid, expected) = match op.expected() {
+            Some(check) => check,
+            None => continue,
+        };
+        if let Err(e) = store::check_component_revision(ctx.kv(), rid, Some(expected)) {
+            if e.to_string() == store::REVISION_CONFLICT {
+                return reply(
+                    ctx,
+                    msg,
+                    &codec::gateway::error_conflict(&format!(
+                        "Operation {}: {}",
+                        i,
+                        store::REVISION_CONFLICT
+                    )),
+                );
+            }
+            return Err(e);
+        }
+    }
+
+    // Make room for the components the batch adds, once it is known the batch can go ahead
+    let count: i32 = ops.iter().map(BatchOp::count).sum();
+    if count > 0 {
+        if let Err(err) = reserve_shard_capacity(ctx, shard, count as u32)? {
+            return reply(ctx, msg, &err);
+        }
+    }
+
+    let mut events = vec![];
+    let mut results = vec![];
+    let mut entities_changed = false;
+    for op in &ops {
+        match op {
+            BatchOp::Set(rid, plan) => {
+                let rev = if plan.writes() {
+                    let write = store::write_component(
+                        ctx.kv(),
+                        rid,
+                        &serde_json::to_string(&plan.model)?,
+                        plan.ttl,
+                    )?;
+                    claim_entity(ctx, msg, rid)?;
+                    if let Some(idx) = write.entity_index {
+                        events.extend(entity_component_add_event(rid, idx));
+                        entities_changed = true;
+                    }
+                    write.revision
+                } else {
+                    store::component_revision(ctx.kv(), rid)?
+                };
+                if !plan.changed.is_empty() {
+                    events.push(ResEvent::change(&rid.to_string(), plan.changed_values(rev)));
+                }
+                results.push(json!({ REVISION_PROPERTY: rev }));
+            }
+            BatchOp::New(rid, plan) => {
+                let added = store::add_component_to_collection(
+                    ctx.kv(),
+                    rid,
+                    &serde_json::to_string(&plan.item)?,
+                    plan.ttl,
+                )?;
+                claim_entity(ctx, msg, rid)?;
+                events.push(ResEvent::add(
+                    &rid.to_string(),
+                    &added.item_rid,
+                    added.index,
+                ));
+                if let Some(idx) = added.entity_index {
+                    events.extend(entity_component_add_event(rid, idx));
+                    entities_changed = true;
+                }
+                results.push(json!({ "rid": added.item_rid }));
+            }
+            BatchOp::Delete { rid, .. } => {
+                let entity_index = store::delete_component(ctx.kv(), rid, None)?;
+                events.push(ResEvent::delete(&rid.to_string()));
+                if let Some(idx) = entity_index {
+                    events.extend(entity_component_remove_event(rid, idx));
+                    entities_changed = true;
+                }
+                results.push(serde_json::Value::Null);
+            }
+            BatchOp::Remove { rid, item, .. } => {
+                let idx = store::remove_component_from_collection(
+                    ctx.kv(),
+                    rid,
+                    &item.to_string(),
+                    None,
+                )?;
+                events.push(ResEvent::remove(&rid.to_string(), idx));
+                events.push(ResEvent::delete(&item.to_string()));
+                results.push(serde_json::Value::Null);
+            }
+        }
+    }
+
+    for event in &events {
+        publish_event(ctx, event)?;
+    }
+    if count < 0 {
+        publish_update_shard(ctx, shard, count)?;
+    }
+    if entities_changed {
+        publish_entities_query(ctx, shard)?;
+    }
+    reply(
+        ctx,
+        msg,
+        &codec::gateway::call_result(json!({ "results": results })),
+    )
+}
+
+/// Checks one operation of a batch on the given shard
+fn plan_batch_op(
+    ctx: &CapabilitiesContext,
+    shard: &str,
+    operation: &serde_json::Value,
+) -> Checked<BatchOp> {
+    let rid = match operation["rid"].as_str().map(|rid| rid.parse::<Rid>()) {
+        Some(Ok(rid)) => rid,
+        Some(Err(e)) => return Ok(Err(codec::gateway::error_invalid_params(&e.to_string()))),
+        None => {
+            return Ok(Err(codec::gateway::error_invalid_params(
+                "rid must be a string",
+            )))
+        }
+    };
+    if !is_component(&rid) {
+        return Ok(Err(not_a_component(&rid)));
+    }
+    if rid.shard() != Some(shard) {
+        return Ok(Err(codec::gateway::error_invalid_params(&format!(
+            "{} is not in shard {}",
+            rid, shard
+        ))));
+    }
+    let params = &operation["params"];
+    match operation["op"].as_str() {
+        Some("set") => Ok(plan_set(ctx, &rid, params)?.map(|plan| BatchOp::Set(rid, plan))),
+        Some("new") => Ok(plan_new(ctx, &rid, params)?.map(|plan| BatchOp::New(rid, plan))),
+        Some("delete") => plan_batch_delete(ctx, rid, params),
+        _ => Ok(Err(codec::gateway::error_invalid_params(
+            "op must be one of set, new or delete",
+        ))),
+    }
+}
+
+/// Checks a delete in a batch. Unlike a delete call of its own, a model component must
+/// exist. A delete on a collection removes the item named by `params.rid`
+fn plan_batch_delete(
+    ctx: &CapabilitiesContext,
+    rid: Rid,
+    params: &serde_json::Value,
+) -> Checked<BatchOp> {
+    let expected = match expected_revision(params) {
+        Ok(expected) => expected,
+        Err(err) => return Ok(Err(err)),
+    };
+    let (rid, item) = match store::item_collection(&rid) {
+        Some(collection) => (collection, rid.to_string()),
+        None => match store::component_type(ctx.kv(), &rid)? {
+            store::ComponentType::Model => return plan_batch_model_delete(ctx, rid, expected),
+            store::ComponentType::Collection => match params["rid"].as_str() {
+                Some(item) => (rid, item.to_string()),
+                None => {
+                    return Ok(Err(codec::gateway::error_invalid_params(
+                        "Removing from a collection needs the rid of the item",
+                    )))
+                }
+            },
+        },
     };
+    if !store::get_collection_rids(ctx.kv(), &rid)?.contains(&item) {
+        return Ok(Err(codec::gateway::error_not_found(&format!(
+            "No such item in {}: {}",
+            rid, item
+        ))));
+    }
+    match item.parse() {
+        Ok(item) => Ok(Ok(BatchOp::Remove {
+            rid,
+            item,
+            expected,
+        })),
+        Err(e) => Ok(Err(codec::gateway::error_invalid_params(&e.to_string()))),
+    }
+}
+
+/// Checks the delete of a model in a batch, which must exist
+fn plan_batch_model_delete(
+    ctx: &CapabilitiesContext,
+    rid: Rid,
+    expected: Option<i32>,
+) -> Checked<BatchOp> {
+    match store::get_component(ctx.kv(), &rid) {
+        Ok(_) => Ok(Ok(BatchOp::Delete { rid, expected })),
+        Err(ref e) if e.to_string() == store::NO_SUCH_COMPONENT => Ok(Err(
+            codec::gateway::error_not_found(&format!("No such component: {}", rid)),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Name of the model property through which a component's revision is exposed. It is
+/// reserved: values for it in set params are ignored
+const REVISION_PROPERTY: &str = "revision";
+
+/// Name of the set and delete param carrying the revision the caller expects the component
+/// to be at
+const EXPECTED_REVISION_PARAM: &str = "expectedRevision";
+
+/// Name of the set and new param carrying the time to live of the component, in seconds
+const TTL_PARAM: &str = "ttl";
+
+/// Reads the optional time to live from request params, returning the error to reply with
+/// if it is present but not a positive integer
+fn ttl_param(params: &serde_json::Value) -> std::result::Result<Option<u32>, serde_json::Value> {
+    match &params[TTL_PARAM] {
+        serde_json::Value::Null => Ok(None),
+        v => v
+            .as_u64()
+            .filter(|ttl| *ttl > 0 && *ttl <= u64::from(u32::MAX))
+            .map(|ttl| Some(ttl as u32))
+            .ok_or_else(|| {
+                codec::gateway::error_invalid_params("ttl must be a positive number of seconds")
+            }),
+    }
+}
+
+/// Reads the optional expected revision from request params, returning the error to reply
+/// with if it is present but not an integer
+fn expected_revision(
+    params: &serde_json::Value,
+) -> std::result::Result<Option<i32>, serde_json::Value> {
+    match &params[EXPECTED_REVISION_PARAM] {
+        serde_json::Value::Null => Ok(None),
+        v => v.as_i64().map(|rev| Some(rev as i32)).ok_or_else(|| {
+            codec::gateway::error_invalid_params("expectedRevision must be an integer")
+        }),
+    }
+}
+
+const GAMELOOP_SUFFIX: &str = ".gameloop";
+
+/// Sweep for lapsed components every this many game loop ticks of a shard
+const EXPIRY_SWEEP_EVERY_TICKS: u64 = 10;
+
+/// Upon receipt of a game loop tick, periodically tidies up after the shard's components
+/// whose time to live has passed, publishing the events that describe their removal
+fn handle_gameloop(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    let gtick: codec::timer::GameLoopTick = serde_json::from_slice(&msg.body)?;
+    if !gtick.seq_no.is_multiple_of(EXPIRY_SWEEP_EVERY_TICKS) {
+        return Ok(vec![]);
+    }
+    for expired in store::sweep_expired(ctx.kv(), &gtick.shard)? {
+        match expired {
+            store::Expired::Component { rid, entity_index } => {
+                ctx.log(&format!("Component {} has expired", rid));
+                publish_event(ctx, &ResEvent::delete(&rid.to_string()))?;
+                if let Some(idx) = entity_index {
+                    publish_entity_component_remove(ctx, &rid, idx)?;
+                }
+                publish_update_shard(ctx, &gtick.shard, -1)?;
+            }
+            store::Expired::Item {
+                rid,
+                collection,
+                index,
+            } => {
+                ctx.log(&format!("Collection item {} has expired", rid));
+                publish_event(ctx, &ResEvent::delete(&rid.to_string()))?;
+                match index {
+                    Some(idx) => publish_collection_remove(ctx, &collection, idx)?,
+                    None => publish_update_shard(ctx, &gtick.shard, -1)?,
+                }
+            }
+        }
+    }
     Ok(vec![])
 }
 
+/// How long to wait for the shard manager to answer a reservation
+const SHARD_REQUEST_TIMEOUT_MS: u64 = 1_000;
+
+/// Asks the shard manager for room for `amount` more components in a shard before they
+/// are written. The shard manager checks the shard's capacity and counts the components
+/// as one, so a granted reservation must be handed back with `publish_update_shard` if
+/// the write then fails. Returns the error to reply with if the shard is full or unknown
+fn reserve_shard_capacity(ctx: &CapabilitiesContext, shard: &str, amount: u32) -> Checked<()> {
+    let out = json!({
+        "params": {
+            "amount": amount
+        }
+    });
+    let subject = format!("call.decs.shard.{}.reserve", shard);
+    let response = ctx.msg().request(
+        &subject,
+        &serde_json::to_vec(&out)?,
+        SHARD_REQUEST_TIMEOUT_MS,
+    )?;
+    let response: serde_json::Value = serde_json::from_slice(&response)?;
+    if response["error"].is_null() {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(response))
+    }
+}
+
+/// Tells the shard manager its count of a shard's components changed, e.g. to hand back
+/// what was reserved for components that have since gone
 fn publish_update_shard(ctx: &CapabilitiesContext, shard: &str, amount: i32) -> Result<()> {
     let out = json!({
         "params": {
@@ -246,53 +2193,133 @@ fn publish_update_shard(ctx: &CapabilitiesContext, shard: &str, amount: i32) ->
 
 fn publish_collection_add(
     ctx: &CapabilitiesContext,
-    rid: &str,
+    rid: &Rid,
     item_rid: &str,
     idx: usize,
 ) -> Result<()> {
-    let subject = format!("event.{}.add", rid);
-    let shard = shard_from_rid(rid);
+    let event = ResEvent::add(&rid.to_string(), item_rid, idx);
+    ctx.log(&format!(
+        "Publishing Collection Add, subject: {}",
+        event.subject
+    ));
+    publish_event(ctx, &event) // the item was counted when its room was reserved
+}
 
-    let resid = decs::gateway::ResourceIdentifier {
-        rid: item_rid.to_string(),
-    };
-    let out = json!({
-        "value" : resid,
-        "idx": idx
-    });
-    ctx.log(&format!("Publishing Collection Add, subject: {}", subject));
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    publish_update_shard(ctx, &shard, 1)?; // increment component count by 1
+/// Publishes the add event on an entity's collection for a component new to the entity
+fn publish_entity_component_add(ctx: &CapabilitiesContext, rid: &Rid, idx: usize) -> Result<()> {
+    if let Some(event) = entity_component_add_event(rid, idx) {
+        publish_event(ctx, &event)?;
+        publish_entities_query(ctx, shard_from_rid(rid))?;
+    }
+    Ok(())
+}
+
+/// The add event on an entity's collection for a component new to the entity
+fn entity_component_add_event(rid: &Rid, idx: usize) -> Option<ResEvent> {
+    let entity_rid = rid.entity_rid()?;
+    let (shard, entity, name) = store::component_parts(rid).ok()?;
+    let component_rid = Rid::component(shard, entity, name);
+    Some(ResEvent::add(
+        &entity_rid.to_string(),
+        &component_rid.to_string(),
+        idx,
+    ))
+}
+
+/// Publishes the remove event on an entity's collection for a component it no longer has
+fn publish_entity_component_remove(ctx: &CapabilitiesContext, rid: &Rid, idx: usize) -> Result<()> {
+    if let Some(event) = entity_component_remove_event(rid, idx) {
+        publish_event(ctx, &event)?;
+        publish_entities_query(ctx, shard_from_rid(rid))?;
+    }
     Ok(())
 }
 
-fn publish_collection_remove(ctx: &CapabilitiesContext, rid: &str, idx: usize) -> Result<()> {
-    let subject = format!("event.{}.remove", rid);
+/// The remove event on an entity's collection for a component it no longer has
+fn entity_component_remove_event(rid: &Rid, idx: usize) -> Option<ResEvent> {
+    let entity_rid = rid.entity_rid()?;
+    Some(ResEvent::remove(&entity_rid.to_string(), idx))
+}
+
+/// Publishes a query event on the shard's entities, as the set of entities or the
+/// components of one of them have changed
+fn publish_entities_query(ctx: &CapabilitiesContext, shard: &str) -> Result<()> {
+    let rid = Rid::Entities(shard.to_string()).to_string();
+    publish_event(
+        ctx,
+        &ResEvent::query(&rid, &format!("{}{}", QUERY_SUBJECT_PREFIX, rid)),
+    )
+}
+
+fn publish_collection_remove(ctx: &CapabilitiesContext, rid: &Rid, idx: usize) -> Result<()> {
+    let event = ResEvent::remove(&rid.to_string(), idx);
     let shard = shard_from_rid(rid);
-    let out = json!({ "idx": idx });
     ctx.log(&format!(
         "Publishing collection remove, subject: {}, idx: {}",
-        subject, idx,
+        event.subject, idx,
     ));
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    publish_update_shard(ctx, &shard, -1)?; // decrement component count by 1
+    publish_event(ctx, &event)?;
+    publish_update_shard(ctx, shard, -1)?; // decrement component count by 1
     Ok(())
 }
 
 fn publish_model_change(
     ctx: &CapabilitiesContext,
     comp: serde_json::Value,
-    rid: &str,
+    rid: &Rid,
 ) -> Result<()> {
-    let subject = format!("event.{}.change", rid);
+    let event = ResEvent::change(&rid.to_string(), comp);
+    ctx.log(&format!(
+        "Publishing Model Change, subject: {}",
+        event.subject
+    ));
+    publish_event(ctx, &event)
+}
 
-    let out = json!({ "values": comp });
-    ctx.log(&format!("Publishing Model Change, subject: {}", subject));
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    Ok(())
+fn publish_event(ctx: &CapabilitiesContext, event: &ResEvent) -> Result<()> {
+    ctx.msg().publish(&event.subject, None, &event.body())
+}
+
+/// Indicates whether a resource ID refers to a single component (or collection item),
+/// rather than a whole entity or shard
+/// Indicates whether a resource ID names a component or a collection item. Items are only
+/// ever given numeric IDs, so anything else after a component's name, such as the `type`,
+/// `rev` and `id` suffixes the store keeps metadata under, is not a component
+fn is_component(rid: &Rid) -> bool {
+    match rid {
+        Rid::Component {
+            component: Some(_),
+            item,
+            ..
+        } => item
+            .as_deref()
+            .is_none_or(|item| !item.is_empty() && item.bytes().all(|b| b.is_ascii_digit())),
+        _ => false,
+    }
+}
+
+fn reply_not_a_component(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: &Rid,
+) -> CallResult {
+    reply(ctx, msg, &not_a_component(rid))
+}
+
+fn not_a_component(rid: &Rid) -> serde_json::Value {
+    codec::gateway::error_invalid_params(&format!("not a component resource: {}", rid))
+}
+
+fn reply(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    result: &serde_json::Value,
+) -> CallResult {
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(result)?)?;
+    }
+    Ok(vec![])
 }
 
 fn extract_model_from_set(body: &[u8]) -> Result<serde_json::Value> {
@@ -302,13 +2329,63 @@ fn extract_model_from_set(body: &[u8]) -> Result<serde_json::Value> {
 }
 
 // decs.components.(shard).(entity).x.y
-fn shard_from_rid(rid: &str) -> String {
-    let tokens: Vec<&str> = rid.split('.').collect();
-    tokens[2].to_string()
+fn shard_from_rid(rid: &Rid) -> &str {
+    rid.shard().unwrap_or_default()
 }
 
 #[cfg(test)]
 mod test {
+    use super::{closed_to_entities_error, entity_access, EntityAccess};
+    use decscloud_common::kv::{KeyValue, MemoryStore};
+    use decscloud_common::users::{AccessToken, ROLE_ADMIN};
+
+    fn token(user: &str, roles: &[&str]) -> AccessToken {
+        AccessToken {
+            user_id: user.to_string(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_entity_access() {
+        let members = vec!["alice".to_string(), "bob".to_string()];
+        let alice = token("alice", &[]);
+        let bob = token("bob", &[]);
+        let eve = token("eve", &[]);
+        let admin = token("root", &[ROLE_ADMIN]);
+
+        assert_eq!(
+            entity_access(None, Some("alice"), &members),
+            EntityAccess::Denied
+        );
+        assert_eq!(
+            entity_access(Some(&alice), Some("alice"), &members),
+            EntityAccess::Full
+        );
+        assert_eq!(
+            entity_access(Some(&bob), Some("alice"), &members),
+            EntityAccess::ReadOnly
+        );
+        assert_eq!(
+            entity_access(Some(&eve), Some("alice"), &members),
+            EntityAccess::Denied
+        );
+        assert_eq!(
+            entity_access(Some(&admin), Some("alice"), &[]),
+            EntityAccess::Full
+        );
+        assert_eq!(entity_access(Some(&eve), None, &[]), EntityAccess::Denied);
+        assert_eq!(
+            entity_access(Some(&bob), None, &members),
+            EntityAccess::ReadOnly
+        );
+        assert_eq!(entity_access(Some(&admin), None, &[]), EntityAccess::Full);
+        assert_eq!(
+            entity_access(Some(&bob), Some("*"), &members),
+            EntityAccess::ReadOnly
+        );
+    }
+
     #[test]
     fn test_extract_from_set() {
         let set_payload = br#"
@@ -324,4 +2401,223 @@ mod test {
         let val = super::extract_model_from_set(set_payload).unwrap();
         assert_eq!(val["mag"], 20);
     }
+
+    #[test]
+    fn test_expected_revision() {
+        assert_eq!(super::expected_revision(&json!({"x": 1})), Ok(None));
+        assert_eq!(super::expected_revision(&json!(null)), Ok(None));
+        assert_eq!(
+            super::expected_revision(&json!({"x": 1, "expectedRevision": 4})),
+            Ok(Some(4))
+        );
+        let err = super::expected_revision(&json!({"expectedRevision": "4"})).unwrap_err();
+        assert_eq!(err["error"]["code"], "system.invalidParams");
+        assert_eq!(
+            json!({ super::REVISION_PROPERTY: 4 }),
+            json!({"revision": 4})
+        );
+    }
+
+    #[test]
+    fn test_entity_query() {
+        use super::EntityQuery;
+
+        let q: EntityQuery = "without=dead&with=velocity,position&limit=10"
+            .parse()
+            .unwrap();
+        assert_eq!(q.with, vec!["position", "velocity"]);
+        assert_eq!(q.without, vec!["dead"]);
+        assert_eq!((q.offset, q.limit), (0, 10));
+        assert_eq!(
+            q.to_string(),
+            "with=position,velocity&without=dead&offset=0&limit=10"
+        );
+        assert_eq!(q.to_string().parse::<EntityQuery>().unwrap(), q);
+
+        let q: EntityQuery = "with=a&with=b,a&offset=50".parse().unwrap();
+        assert_eq!(q.to_string(), "with=a,b&offset=50&limit=25");
+        assert_eq!("".parse::<EntityQuery>().unwrap(), EntityQuery::default());
+
+        assert!("with=".parse::<EntityQuery>().is_err());
+        assert!("with=a..b".parse::<EntityQuery>().is_err());
+        assert!("without=a,*".parse::<EntityQuery>().is_err());
+        assert!("limit=0".parse::<EntityQuery>().is_err());
+        assert!("limit=101".parse::<EntityQuery>().is_err());
+        assert!("offset=-1".parse::<EntityQuery>().is_err());
+        assert!("sort=name".parse::<EntityQuery>().is_err());
+    }
+
+    #[test]
+    fn test_batch_op_claims() {
+        use super::{BatchOp, PlannedSet};
+        let rid = |s: &str| s.parse::<decscloud_common::gateway::Rid>().unwrap();
+        let set = |existed: bool, changed: serde_json::Value, expected: Option<i32>| {
+            BatchOp::Set(
+                rid("decs.components.the_void.ship1.position"),
+                PlannedSet {
+                    model: json!({}),
+                    changed: changed.as_object().unwrap().clone(),
+                    existed,
+                    counted: existed,
+                    expected,
+                    ttl: None,
+                },
+            )
+        };
+
+        // a set is checked against its expected revision whether or not it writes anything
+        assert_eq!(
+            set(true, json!({"x": 1}), Some(3))
+                .expected()
+                .map(|(_, e)| e),
+            Some(3)
+        );
+        assert_eq!(
+            set(true, json!({}), Some(3)).expected().map(|(_, e)| e),
+            Some(3)
+        );
+        assert!(set(false, json!({}), None).expected().is_none());
+
+        let delete = BatchOp::Delete {
+            rid: rid("decs.components.the_void.ship1.position"),
+            expected: None,
+        };
+        assert!(delete.expected().is_none());
+        assert_eq!(
+            delete.target().unwrap().to_string(),
+            "decs.components.the_void.ship1.position"
+        );
+
+        let remove = BatchOp::Remove {
+            rid: rid("decs.components.the_void.ship1.cargo"),
+            item: rid("decs.components.the_void.ship1.cargo.2"),
+            expected: Some(1),
+        };
+        let (checked, expected) = remove.expected().unwrap();
+        assert_eq!(
+            checked.to_string(),
+            "decs.components.the_void.ship1.cargo.2"
+        );
+        assert_eq!(expected, 1);
+        assert_eq!(
+            remove.rid().to_string(),
+            "decs.components.the_void.ship1.cargo"
+        );
+
+        // only components new to the shard need room reserved for them
+        assert_eq!(set(false, json!({"x": 1}), None).count(), 1);
+        assert_eq!(set(true, json!({"x": 1}), None).count(), 0);
+        assert_eq!(delete.count() + remove.count(), -2);
+    }
+
+    #[test]
+    fn test_index_param() {
+        assert_eq!(super::index_param(&json!({"idx": 0})), Ok(0));
+        assert_eq!(super::index_param(&json!({"idx": 3, "mass": 1})), Ok(3));
+        for bad in [json!(null), json!(-1), json!(1.5), json!("2")] {
+            let err = super::index_param(&json!({ "idx": bad })).unwrap_err();
+            assert_eq!(err["error"]["code"], "system.invalidParams");
+        }
+    }
+
+    #[test]
+    fn test_ttl_param() {
+        assert_eq!(super::ttl_param(&json!({"x": 1})), Ok(None));
+        assert_eq!(super::ttl_param(&json!({"ttl": 30})), Ok(Some(30)));
+        for bad in [
+            json!(0),
+            json!(-5),
+            json!(1.5),
+            json!("30"),
+            json!(1u64 << 40),
+        ] {
+            let err = super::ttl_param(&json!({ "ttl": bad })).unwrap_err();
+            assert_eq!(err["error"]["code"], "system.invalidParams");
+        }
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let prefab = json!({
+            "hull": {"integrity": 100, "armor": 5},
+            "cargo": {"capacity": 50},
+        });
+        let overrides = json!({
+            "hull": {"armor": null, "integrity": 80},
+            "cargo": null,
+            "position": {"x": 10},
+        });
+        let bundle = super::apply_overrides(
+            prefab.as_object().unwrap().clone(),
+            overrides.as_object().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::Value::Object(bundle),
+            json!({"hull": {"integrity": 80}, "position": {"x": 10}})
+        );
+
+        let bad = json!({"hull": 3});
+        assert!(super::apply_overrides(
+            prefab.as_object().unwrap().clone(),
+            bad.as_object().unwrap()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_bundle() {
+        let ok = json!({"position": {"x": 1}, "radar_contacts": {}});
+        assert!(super::check_bundle(ok.as_object().unwrap()).is_ok());
+        let bad_name = json!({"pos.x": {"x": 1}});
+        assert!(super::check_bundle(bad_name.as_object().unwrap()).is_err());
+        let bad_value = json!({"position": [1, 2]});
+        assert!(super::check_bundle(bad_value.as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_empty_spawn_into_draining_shard() {
+        let kv = MemoryStore::new();
+        assert!(closed_to_entities_error(&kv, "zone1").unwrap().is_none());
+        kv.set(
+            "decs:shard:zone1",
+            r#"{"name":"zone1","capacity":10,"state":"draining"}"#,
+            None,
+        )
+        .unwrap();
+
+        // an empty bundle reserves nothing, so only the state check can turn it away
+        let empty = json!({});
+        assert!(super::check_bundle(empty.as_object().unwrap()).is_ok());
+        let err = closed_to_entities_error(&kv, "zone1").unwrap().unwrap();
+        assert_eq!(err["error"]["code"], "decs.shardClosed");
+        assert_eq!(err["error"]["data"]["state"], "draining");
+    }
+
+    #[test]
+    fn test_valid_shard_name() {
+        assert!(super::valid_shard_name("zone2"));
+        for bad in ["", "zone.2", "zone 2", "zone>"] {
+            assert!(!super::valid_shard_name(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_is_component() {
+        let rid = |s: &str| s.parse::<decscloud_common::gateway::Rid>().unwrap();
+        assert!(super::is_component(&rid(
+            "decs.components.the_void.ship1.position"
+        )));
+        assert!(super::is_component(&rid(
+            "decs.components.the_void.ship1.cargo.3"
+        )));
+        for metadata in ["type", "rev", "id"] {
+            assert!(!super::is_component(&rid(&format!(
+                "decs.components.the_void.ship1.cargo.{}",
+                metadata
+            ))));
+        }
+        assert!(!super::is_component(&rid("decs.components.the_void.ship1")));
+        assert!(!super::is_component(&rid("decs.components.the_void")));
+    }
 }
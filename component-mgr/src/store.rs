@@ -1,20 +1,182 @@
-use guest::prelude::*;
+use decscloud_common as codec;
+use decscloud_common::components::{
+    component_entities_key, entity_components_key, item_id_key, item_key, lock_key, owner_key,
+    revision_key, shard_entities_key, shard_expiring_key, shard_users_key, type_key,
+    TYPE_COLLECTION, TYPE_MODEL,
+};
+use decscloud_common::gateway::Rid;
+use decscloud_common::kv::KeyValue;
+use decscloud_common::shard::{Shard, ShardState};
 
 pub enum ComponentType {
     Collection,
     Model,
 }
 
-const TYPE_MODEL: &str = "M";
-const TYPE_COLLECTION: &str = "C";
-
 pub(crate) const NO_SUCH_COMPONENT: &str = "no such component";
+pub(crate) const NO_SUCH_ITEM: &str = "no such item in the collection";
+pub(crate) const REVISION_CONFLICT: &str = "component was changed since the expected revision";
+pub(crate) const INDEX_OUT_OF_RANGE: &str = "index is out of range for the collection";
+pub(crate) const ENTITY_EXISTS: &str = "the shard already has an entity with that ID";
+pub(crate) const COLLECTION_BUSY: &str = "the collection is being changed by another request";
+
+/// How long the lock on a collection's order lasts should its holder never release it
+const COLLECTION_LOCK_TTL_SECS: u32 = 10;
+
+/// Owner recorded for entities first written without a connection token, i.e. by
+/// server-side systems rather than players. Never a valid user ID, as `*` cannot
+/// appear in a resource ID
+pub(crate) const SYSTEM_OWNER: &str = "*";
+
+const ENTITY_SEQ_KEY: &str = "decs:components:entity_seq";
+
+/// Retrieves the state of a shard from the details the shard manager keeps for it. A shard
+/// without details yet is taken to be running
+pub(crate) fn shard_state(kv: &impl KeyValue, shard: &str) -> codec::kv::Result<ShardState> {
+    match kv.get(&format!("decs:shard:{}", shard))? {
+        Some(v) => Ok(serde_json::from_str::<Shard>(&v)?.state),
+        None => Ok(ShardState::default()),
+    }
+}
+
+/// Generates the ID of a newly spawned entity
+pub(crate) fn next_entity_id(kv: &impl KeyValue) -> codec::kv::Result<String> {
+    codec::ids::next_uuid(kv, ENTITY_SEQ_KEY)
+}
+
+/// Retrieves the names of all components (models and collections) an entity has
+pub(crate) fn entity_components(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+) -> codec::kv::Result<Vec<String>> {
+    kv.list_range(&entity_components_key(shard, entity), 0, -1)
+}
+
+/// Indicates whether an entity exists, i.e. has components or has been claimed
+pub(crate) fn entity_exists(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+) -> codec::kv::Result<bool> {
+    Ok(
+        kv.exists(&entity_components_key(shard, entity))?
+            || kv.exists(&owner_key(shard, entity))?,
+    )
+}
+
+/// Records that an entity has a component, if it isn't recorded already. Returns the
+/// index at which the component was added to the entity's collection, if it was
+fn index_entity_component(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+    component: &str,
+) -> codec::kv::Result<Option<usize>> {
+    if entity_components(kv, shard, entity)?
+        .iter()
+        .any(|c| c == component)
+    {
+        return Ok(None);
+    }
+    kv.list_add(&entity_components_key(shard, entity), component)?;
+    Ok(Some(0)) // new components are pushed onto the head of the list
+}
+
+/// Removes a component from an entity's collection. Returns the index it occupied, if it
+/// was there
+fn unindex_entity_component(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+    component: &str,
+) -> codec::kv::Result<Option<usize>> {
+    let key = entity_components_key(shard, entity);
+    let idx = entity_components(kv, shard, entity)?
+        .iter()
+        .position(|c| c == component);
+    if idx.is_some() {
+        kv.list_del_item(&key, component)?;
+    }
+    Ok(idx)
+}
+
+/// Retrieves the ID of the user owning an entity, if the entity has been claimed
+pub(crate) fn entity_owner(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+) -> codec::kv::Result<Option<String>> {
+    kv.get(&owner_key(shard, entity))
+}
+
+/// Retrieves the IDs of all users owning at least one entity in the shard
+pub(crate) fn shard_users(kv: &impl KeyValue, shard: &str) -> codec::kv::Result<Vec<String>> {
+    kv.set_members(&shard_users_key(shard))
+}
+
+/// Records the owner of an entity, unless it has already been claimed. Owning an
+/// entity also makes the user a member of the entity's shard. Every write claims its
+/// entity, so this is also where the entity joins the shard's set of entities
+pub(crate) fn claim_entity(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+    owner: &str,
+) -> codec::kv::Result<()> {
+    let key = owner_key(shard, entity);
+    if !kv.exists(&key)? {
+        kv.set(&key, owner, None)?;
+        if owner != SYSTEM_OWNER {
+            kv.set_add(&shard_users_key(shard), owner)?;
+        }
+    }
+    kv.set_add(&shard_entities_key(shard), entity)?;
+    Ok(())
+}
+
+/// Retrieves the IDs of the entities in a shard which have every one of the `with`
+/// components and none of the `without` components, sorted so that pages of the
+/// result are stable. With no `with` components, every entity in the shard qualifies
+pub(crate) fn query_entities(
+    kv: &impl KeyValue,
+    shard: &str,
+    with: &[String],
+    without: &[String],
+) -> codec::kv::Result<Vec<String>> {
+    let mut entities = if with.is_empty() {
+        kv.set_members(&shard_entities_key(shard))?
+    } else {
+        let keys: Vec<String> = with
+            .iter()
+            .map(|c| component_entities_key(shard, c))
+            .collect();
+        kv.set_intersect(keys.as_slice())?
+    };
+    if !without.is_empty() {
+        let keys: Vec<String> = without
+            .iter()
+            .map(|c| component_entities_key(shard, c))
+            .collect();
+        let excluded = kv.set_union(keys.as_slice())?;
+        entities.retain(|e| !excluded.contains(e));
+    }
+    entities.sort();
+    Ok(entities)
+}
+
+/// Indicates whether a component or collection item counts towards its shard. It does from
+/// its first write until it is deleted or swept up, including once its time to live has
+/// passed but before the sweep, which is why this looks for its type rather than its value
+pub(crate) fn component_counted(kv: &impl KeyValue, rid: &Rid) -> codec::kv::Result<bool> {
+    kv.exists(&type_key(&rid.to_key()))
+}
 
 /// Examines the type metadata for a given rid, returning whether it is a
 /// model or a collection
-pub(crate) fn component_type(ctx: &CapabilitiesContext, rid: &str) -> Result<ComponentType> {
-    let key = format!("{}:type", rid.replace('.', ":"));
-    let typeval = ctx.kv().get(&key)?;
+pub(crate) fn component_type(kv: &impl KeyValue, rid: &Rid) -> codec::kv::Result<ComponentType> {
+    let key = type_key(&rid.to_key());
+    let typeval = kv.get(&key)?;
     match typeval {
         Some(v) => {
             if v == TYPE_COLLECTION {
@@ -27,176 +189,1110 @@ pub(crate) fn component_type(ctx: &CapabilitiesContext, rid: &str) -> Result<Com
     }
 }
 
-pub(crate) fn get_collection_rids(ctx: &CapabilitiesContext, rid: &str) -> Result<Vec<String>> {
-    ctx.kv().list_range(&rid.replace('.', ":"), 0, -1)
+pub(crate) fn get_collection_rids(kv: &impl KeyValue, rid: &Rid) -> codec::kv::Result<Vec<String>> {
+    kv.list_range(&rid.to_key(), 0, -1)
+}
+
+/// Retrieves the current revision of a component. A component that has never been
+/// written is at revision 0
+pub(crate) fn component_revision(kv: &impl KeyValue, rid: &Rid) -> codec::kv::Result<i32> {
+    stored_revision(kv, &rid.to_key())
+}
+
+/// When an expected revision is given, fails with `REVISION_CONFLICT` unless the component
+/// stored under `key` is at that revision. This is a plain read ahead of the write, so the
+/// compare is only best-effort: two writers expecting the same revision at the same moment
+/// may both pass it. The write itself is never undone, and the revision is only moved on
+/// once the write has been made
+fn check_revision(kv: &impl KeyValue, key: &str, expected: Option<i32>) -> codec::kv::Result<()> {
+    match expected {
+        Some(expected) if stored_revision(kv, key)? != expected => Err(REVISION_CONFLICT.into()),
+        _ => Ok(()),
+    }
+}
+
+fn stored_revision(kv: &impl KeyValue, key: &str) -> codec::kv::Result<i32> {
+    match kv.get(&revision_key(key))? {
+        Some(rev) => Ok(rev.parse().unwrap_or(0)),
+        None => Ok(0),
+    }
+}
+
+/// Moves the component stored under `key` on to its next revision once it has been written,
+/// returning the new revision
+fn bump_revision(kv: &impl KeyValue, key: &str) -> codec::kv::Result<i32> {
+    kv.atomic_add(&revision_key(key), 1)
+}
+
+/// The outcome of storing a single component value
+#[derive(Debug)]
+pub(crate) struct ComponentWrite {
+    /// The component's revision after the write
+    pub revision: i32,
+    /// Where the component was added to its entity's collection, if it is new to the entity
+    pub entity_index: Option<usize>,
 }
 
-/// Stores a single component value
-pub(crate) fn put_component(ctx: &CapabilitiesContext, rid: &str, component: &str) -> Result<bool> {
-    let tokens: Vec<&str> = rid.split('.').collect();
-    let key = component_key(&tokens);
-    let entkey = component_entities_key(&tokens);
-    let typekey = format!("{}:type", key);
+/// Stores a single component value. With a time to live (in seconds) the value lapses
+/// unless written again in time; without one it is kept until deleted
+pub(crate) fn put_component(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    component: &str,
+    expected_revision: Option<i32>,
+    ttl: Option<u32>,
+) -> codec::kv::Result<ComponentWrite> {
+    check_component_revision(kv, rid, expected_revision)?;
+    write_component(kv, rid, component, ttl)
+}
+
+/// Fails with `REVISION_CONFLICT` if a component is not at the expected revision. Lets
+/// several writes be checked before any of them is made with `write_component`
+pub(crate) fn check_component_revision(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    expected_revision: Option<i32>,
+) -> codec::kv::Result<()> {
+    check_revision(kv, &rid.to_key(), expected_revision)
+}
 
-    let existed = ctx.kv().exists(&key)?;
+/// Stores a single component value without checking its revision, then moves it on to its
+/// next revision
+pub(crate) fn write_component(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    component: &str,
+    ttl: Option<u32>,
+) -> codec::kv::Result<ComponentWrite> {
+    let (shard, entity, name) = component_parts(rid)?;
+    let key = rid.to_key();
+    let entkey = component_entities_key(shard, name);
+    let typekey = type_key(&key);
+
+    kv.set(&typekey, TYPE_MODEL, None)?;
+    kv.set_add(&entkey, entity)?; // add entity to list of entities with a given component
+    kv.set(&key, component, ttl)?;
+    track_expiry(kv, shard, &rid.to_string(), ttl)?;
+    let entity_index = match rid_item(rid) {
+        None => index_entity_component(kv, shard, entity, name)?,
+        Some(_) => None,
+    };
+    Ok(ComponentWrite {
+        revision: bump_revision(kv, &key)?,
+        entity_index,
+    })
+}
 
-    ctx.kv().set(&typekey, TYPE_MODEL, None)?;
-    ctx.kv().set_add(&entkey, &entity_id(&tokens))?; // add entity to list of entities with a given component
-    ctx.kv().set(&key, component, None)?;
-    Ok(existed)
+/// The outcome of adding a value to a collection component
+#[derive(Debug)]
+pub(crate) struct CollectionAdd {
+    /// Index of the new item within the collection
+    pub index: usize,
+    /// Resource ID of the new item
+    pub item_rid: String,
+    /// Where the collection was added to its entity's collection, if it is new to the entity
+    pub entity_index: Option<usize>,
 }
 
-/// Adds a component value to the given collection
+/// Adds a component value to the given collection. The new item is pushed onto the head
+/// of the collection, so it is always added at index 0. With a time to live (in seconds),
+/// the new item lapses and leaves the collection once it has passed
 pub(crate) fn add_component_to_collection(
-    ctx: &CapabilitiesContext,
-    rid: &str,
+    kv: &impl KeyValue,
+    rid: &Rid,
     component: &str,
-) -> Result<(usize, String)> {
-    let tokens: Vec<&str> = rid.split('.').collect();
-    let key = component_key(&tokens);
-    let entkey = component_entities_key(&tokens);
-    let typekey = format!("{}:type", key);
-    let idkey = format!("{}:id", key);
+    ttl: Option<u32>,
+) -> codec::kv::Result<CollectionAdd> {
+    insert_component_into_collection(kv, rid, component, 0, ttl)
+}
 
-    let id = ctx.kv().atomic_add(&idkey, 1)?;
-    let new_rid = format!("{}.{}", rid, id);
+/// Adds a component value to the given collection at the given index, failing with
+/// `INDEX_OUT_OF_RANGE` if the index is past the end of the collection
+pub(crate) fn insert_component_into_collection(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    component: &str,
+    index: usize,
+    ttl: Option<u32>,
+) -> codec::kv::Result<CollectionAdd> {
+    let (shard, entity, name) = component_parts(rid)?;
+    let key = rid.to_key();
+    let entkey = component_entities_key(shard, name);
+    let typekey = type_key(&key);
+    let idkey = item_id_key(&key);
 
-    // add to the collection (the component key)
-    let mut num_added = 0;
-    ctx.kv().set(&typekey, TYPE_COLLECTION, None)?;
-    if !ctx.kv().exists(&new_rid)? {
-        num_added = ctx.kv().list_add(&key, &new_rid)?;
-    }
+    let item_rid = with_collection_lock(kv, &key, || {
+        let mut members = kv.list_range(&key, 0, -1)?;
+        if index > members.len() {
+            return Err(INDEX_OUT_OF_RANGE.into());
+        }
+        // item IDs come from a counter, but skip any left behind by a counter that was reset
+        let item_rid = loop {
+            let id = kv.atomic_add(&idkey, 1)?;
+            let item_rid = format!("{}.{}", rid, id);
+            if !members.contains(&item_rid) && !kv.exists(&item_key(&item_rid))? {
+                break item_rid;
+            }
+        };
 
-    ctx.log(&format!("Adding '{}' to set '{}'", new_rid, key));
+        // add to the collection (the component key)
+        kv.set(&typekey, TYPE_COLLECTION, None)?;
+        if index == 0 {
+            kv.list_add(&key, &item_rid)?;
+        } else {
+            members.insert(index, item_rid.clone());
+            write_collection_order(kv, &key, &members)?;
+        }
+        Ok(item_rid)
+    })?;
 
     // set the individual item
-    let ridkey = new_rid.replace('.', ":");
-    let ridtypekey = format!("{}:type", ridkey);
-    ctx.kv().set(&ridkey, component, None)?;
-    ctx.kv().set(&ridtypekey, TYPE_MODEL, None)?;
-
-    ctx.kv().set_add(&entkey, &entity_id(&tokens))?; // add entity to the set of entities with a given component
-
-    let members = ctx.kv().list_range(&key, 0, -1)?;
-    if num_added == 0 {
-        Ok((
-            members
-                .iter()
-                .position(|cmp| *cmp == rid)
-                .unwrap_or_else(|| members.len() - 1),
-            new_rid.clone(),
-        ))
-    } else {
-        Ok((0, new_rid.clone())) // new item was added to the beginning
+    let itemkey = item_key(&item_rid);
+    let item_type_key = type_key(&itemkey);
+    kv.set(&itemkey, component, ttl)?;
+    kv.set(&item_type_key, TYPE_MODEL, None)?;
+    bump_revision(kv, &itemkey)?;
+    track_expiry(kv, shard, &item_rid, ttl)?;
+
+    kv.set_add(&entkey, entity)?; // add entity to the set of entities with a given component
+    let entity_index = index_entity_component(kv, shard, entity, name)?;
+
+    Ok(CollectionAdd {
+        index,
+        item_rid,
+        entity_index,
+    })
+}
+
+pub(crate) fn get_component(kv: &impl KeyValue, rid: &Rid) -> codec::kv::Result<String> {
+    match kv.get(&rid.to_key())? {
+        Some(s) => Ok(s),
+        None => Err(NO_SUCH_COMPONENT.into()),
+    }
+}
+
+/// Deletes a single component value. Returns the index the component occupied in its
+/// entity's collection, if it was listed there
+pub(crate) fn delete_component(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    expected_revision: Option<i32>,
+) -> codec::kv::Result<Option<usize>> {
+    let (shard, entity, name) = component_parts(rid)?;
+    let key = rid.to_key();
+    let typekey = type_key(&key);
+    let ent_key = component_entities_key(shard, name);
+
+    check_revision(kv, &key, expected_revision)?;
+    kv.del_key(&revision_key(&key))?;
+    kv.del_key(&typekey)?;
+    kv.del_key(&key)?;
+    if rid_item(rid).is_some() {
+        return Ok(None);
+    }
+    kv.set_remove(&ent_key, entity)?;
+    unindex_entity_component(kv, shard, entity, name)
+}
+
+/// Removes an item from a collection, failing with `NO_SUCH_ITEM` if the collection doesn't
+/// have it. Returns the index the item occupied
+pub(crate) fn remove_component_from_collection(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    item_rid: &str,
+    expected_revision: Option<i32>,
+) -> codec::kv::Result<usize> {
+    let key = rid.to_key();
+    let itemkey = item_key(item_rid);
+    let item_type_key = type_key(&itemkey);
+
+    let idx = with_collection_lock(kv, &key, || {
+        let idx = match kv
+            .list_range(&key, 0, -1)?
+            .iter()
+            .position(|i| i == item_rid)
+        {
+            Some(idx) => idx,
+            None => return Err(NO_SUCH_ITEM.into()),
+        };
+        check_revision(kv, &itemkey, expected_revision)?;
+        kv.list_del_item(&key, item_rid)?;
+        Ok(idx)
+    })?;
+    kv.del_key(&itemkey)?;
+    kv.del_key(&item_type_key)?;
+    kv.del_key(&revision_key(&itemkey))?;
+
+    Ok(idx)
+}
+
+/// Moves an item within a collection so that it ends up at the given index, failing with
+/// `NO_SUCH_ITEM` if the collection doesn't have it or `INDEX_OUT_OF_RANGE` if the index is
+/// past its last item. Returns the index the item occupied before the move
+pub(crate) fn move_collection_item(
+    kv: &impl KeyValue,
+    rid: &Rid,
+    item_rid: &str,
+    index: usize,
+) -> codec::kv::Result<usize> {
+    let key = rid.to_key();
+    with_collection_lock(kv, &key, || {
+        let mut members = kv.list_range(&key, 0, -1)?;
+        let from = match members.iter().position(|i| i == item_rid) {
+            Some(from) => from,
+            None => return Err(NO_SUCH_ITEM.into()),
+        };
+        if index >= members.len() {
+            return Err(INDEX_OUT_OF_RANGE.into());
+        }
+        if from != index {
+            let item = members.remove(from);
+            members.insert(index, item);
+            write_collection_order(kv, &key, &members)?;
+        }
+        Ok(from)
+    })
+}
+
+/// Runs `change` while holding the lock on the order of the collection stored under `key`,
+/// failing with `COLLECTION_BUSY`, and changing nothing, if another request holds it. The
+/// key-value capability can't insert into or rearrange a list in one call, so a reorder
+/// rewrites the whole list; holding the lock while a collection's items are added, moved
+/// or removed stops one of those from landing part way through a rewrite and being lost
+/// or duplicated. The lock lapses on its own should its holder never release it
+fn with_collection_lock<T>(
+    kv: &impl KeyValue,
+    key: &str,
+    change: impl FnOnce() -> codec::kv::Result<T>,
+) -> codec::kv::Result<T> {
+    let lock = lock_key(key);
+    if kv.atomic_add(&lock, 1)? != 1 {
+        return Err(COLLECTION_BUSY.into());
     }
+    kv.set(&lock, "1", Some(COLLECTION_LOCK_TTL_SECS))?;
+    let result = change();
+    kv.del_key(&lock)?;
+    result
 }
 
-fn index_of(ctx: &CapabilitiesContext, setkey: &str, item: &str) -> Result<usize> {
-    let members = ctx.kv().list_range(&setkey, 0, -1)?;
-    Ok(members.iter().position(|s| *s == item).unwrap_or_else(|| 0))
+/// Replaces the contents of a collection's list. The key-value capability can only push
+/// onto the head of a list, so placing an item anywhere else means rebuilding it. Lists
+/// others may be changing at the same time are only rebuilt under `with_collection_lock`
+fn write_collection_order(
+    kv: &impl KeyValue,
+    key: &str,
+    members: &[String],
+) -> codec::kv::Result<()> {
+    kv.list_clear(key)?;
+    for member in members.iter().rev() {
+        kv.list_add(key, member)?;
+    }
+    Ok(())
 }
 
-pub(crate) fn get_component(
-    ctx: &CapabilitiesContext,
+/// Records whether a component or collection item was written with a time to live, so
+/// that `sweep_expired` can tidy up after it once it lapses
+fn track_expiry(
+    kv: &impl KeyValue,
+    shard: &str,
     rid: &str,
-) -> std::result::Result<String, Box<dyn std::error::Error>> {
-    let key = rid.replace('.', ":");
-    ctx.log(&format!("Querying single component: {}", key));
-    match ctx.kv().get(&key) {
-        Ok(v) => {
-            if let Some(s) = v {
-                Ok(s)
-            } else {
-                Err(NO_SUCH_COMPONENT.into())
+    ttl: Option<u32>,
+) -> codec::kv::Result<()> {
+    let key = shard_expiring_key(shard);
+    match ttl {
+        Some(_) => kv.set_add(&key, rid)?,
+        None => kv.set_remove(&key, rid)?,
+    };
+    Ok(())
+}
+
+/// A component or collection item whose time to live has passed
+pub(crate) enum Expired {
+    /// A component, with the index it occupied in its entity's collection if it was listed there
+    Component {
+        rid: Rid,
+        entity_index: Option<usize>,
+    },
+    /// A collection item, with its collection and the index it occupied there if it was listed
+    Item {
+        rid: Rid,
+        collection: Rid,
+        index: Option<usize>,
+    },
+}
+
+/// Tidies up after the components and collection items in a shard whose time to live has
+/// passed. The key-value store drops their values on its own; what's left behind is their
+/// metadata and their place in the shard's indexes and collections. Returns what lapsed
+pub(crate) fn sweep_expired(kv: &impl KeyValue, shard: &str) -> codec::kv::Result<Vec<Expired>> {
+    let expiring_key = shard_expiring_key(shard);
+    let mut expired = vec![];
+    for source in kv.set_members(&expiring_key)? {
+        let rid: Rid = match source.parse() {
+            Ok(rid) => rid,
+            Err(_) => {
+                kv.set_remove(&expiring_key, &source)?;
+                continue;
+            }
+        };
+        let key = rid.to_key();
+        let typekey = type_key(&key);
+        if kv.exists(&key)? {
+            continue; // still live
+        }
+        kv.set_remove(&expiring_key, &source)?;
+        if !kv.exists(&typekey)? {
+            continue; // deleted before it lapsed
+        }
+        kv.del_key(&typekey)?;
+        kv.del_key(&revision_key(&key))?;
+        let (_, entity, name) = component_parts(&rid)?;
+        if rid_item(&rid).is_some() {
+            let collection = Rid::component(shard, entity, name);
+            let collection_key = collection.to_key();
+            let members = kv.list_range(&collection_key, 0, -1)?;
+            let index = members.iter().position(|m| *m == source);
+            if index.is_some() {
+                kv.list_del_item(&collection_key, &source)?;
+            }
+            expired.push(Expired::Item {
+                rid,
+                collection,
+                index,
+            });
+        } else {
+            kv.set_remove(&component_entities_key(shard, name), entity)?;
+            let entity_index = unindex_entity_component(kv, shard, entity, name)?;
+            expired.push(Expired::Component { rid, entity_index });
+        }
+    }
+    Ok(expired)
+}
+
+/// What was removed from the store along with an entity
+pub(crate) struct EntityRemoval {
+    /// Resource IDs of the components, collections and items that no longer exist
+    pub deleted: Vec<String>,
+    /// Number of component values (models and collection items) removed from the shard
+    pub count: i32,
+}
+
+/// Deletes an entity: every one of its components, collections and collection items, and
+/// its membership of the shard's component indexes. An owner left with no entities in the
+/// shard stops being one of its users. Returns `None` if there is no such entity
+pub(crate) fn delete_entity(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+) -> codec::kv::Result<Option<EntityRemoval>> {
+    if !entity_exists(kv, shard, entity)? {
+        return Ok(None);
+    }
+    let components = entity_components(kv, shard, entity)?;
+    let mut deleted = vec![];
+    let mut count = 0;
+    for name in components {
+        let rid = Rid::component(shard, entity, &name);
+        let key = rid.to_key();
+        if let ComponentType::Collection = component_type(kv, &rid)? {
+            for item_rid in get_collection_rids(kv, &rid)? {
+                let itemkey = item_key(&item_rid);
+                kv.del_key(&itemkey)?;
+                kv.del_key(&type_key(&itemkey))?;
+                kv.del_key(&revision_key(&itemkey))?;
+                deleted.push(item_rid);
+                count += 1;
+            }
+            kv.list_clear(&key)?;
+            kv.del_key(&item_id_key(&key))?;
+        } else {
+            count += 1;
+        }
+        kv.del_key(&key)?;
+        kv.del_key(&type_key(&key))?;
+        kv.del_key(&revision_key(&key))?;
+        kv.set_remove(&component_entities_key(shard, &name), entity)?;
+        deleted.push(rid.to_string());
+    }
+    kv.del_key(&entity_components_key(shard, entity))?;
+    let owner = entity_owner(kv, shard, entity)?;
+    kv.del_key(&owner_key(shard, entity))?;
+    kv.set_remove(&shard_entities_key(shard), entity)?;
+    if let Some(owner) = owner {
+        if !owns_entity(kv, shard, &owner)? {
+            kv.set_remove(&shard_users_key(shard), &owner)?;
+        }
+    }
+    deleted.push(
+        Rid::Component {
+            shard: shard.to_string(),
+            entity: entity.to_string(),
+            component: None,
+            item: None,
+        }
+        .to_string(),
+    );
+    Ok(Some(EntityRemoval { deleted, count }))
+}
+
+/// Indicates whether the user owns any of the entities in a shard
+fn owns_entity(kv: &impl KeyValue, shard: &str, owner: &str) -> codec::kv::Result<bool> {
+    for entity in kv.set_members(&shard_entities_key(shard))? {
+        if entity_owner(kv, shard, &entity)?.as_deref() == Some(owner) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Counts the component values (models and collection items) an entity holds
+pub(crate) fn entity_value_count(
+    kv: &impl KeyValue,
+    shard: &str,
+    entity: &str,
+) -> codec::kv::Result<i32> {
+    let mut count = 0;
+    for name in entity_components(kv, shard, entity)? {
+        let rid = Rid::component(shard, entity, &name);
+        if let ComponentType::Collection = component_type(kv, &rid)? {
+            for item_rid in get_collection_rids(kv, &rid)? {
+                if kv.exists(&item_key(&item_rid))? {
+                    count += 1;
+                }
             }
+        } else if kv.exists(&rid.to_key())? {
+            count += 1;
         }
-        Err(e) => Err(Box::new(e)),
     }
+    Ok(count)
+}
+
+/// What moving an entity from one shard to another did
+pub(crate) struct EntityMigration {
+    /// What was removed from the old shard, as if the entity had been deleted there
+    pub removal: EntityRemoval,
+    /// Number of component values (models and collection items) added to the new shard
+    pub count: i32,
 }
 
-pub(crate) fn delete_component(ctx: &CapabilitiesContext, rid: &str) -> Result<()> {
-    let tokens: Vec<&str> = rid.split('.').collect();
-    let key = rid.replace('.', ":");
-    let type_key = format!("{}:type", key);
-    let ent_key = component_entities_key(&tokens);
+/// Moves an entity, keeping its ID, to another shard: its components, collections and
+/// their items are copied over with their revisions and the order of its collections, it
+/// keeps its owner, and it is then deleted from the old shard. Values whose time to live
+/// has already passed are left behind, and the rest are permanent in the new shard, as the
+/// time they had left can't be read back from the store. Fails with `ENTITY_EXISTS` if the
+/// new shard already has the entity, and returns `None` if there is no such entity
+pub(crate) fn migrate_entity(
+    kv: &impl KeyValue,
+    from: &str,
+    entity: &str,
+    to: &str,
+) -> codec::kv::Result<Option<EntityMigration>> {
+    if !entity_exists(kv, from, entity)? {
+        return Ok(None);
+    }
+    // claiming the ID in the new shard's entity set keeps concurrent migrations apart
+    if entity_exists(kv, to, entity)? || kv.set_add(&shard_entities_key(to), entity)? == 0 {
+        return Err(ENTITY_EXISTS.into());
+    }
+
+    let mut components = vec![];
+    let mut count = 0;
+    for name in entity_components(kv, from, entity)? {
+        let rid = Rid::component(from, entity, &name);
+        let to_rid = Rid::component(to, entity, &name);
+        let (key, to_key) = (rid.to_key(), to_rid.to_key());
+        if let ComponentType::Collection = component_type(kv, &rid)? {
+            let mut items = vec![];
+            for item_rid in get_collection_rids(kv, &rid)? {
+                let to_item_rid = format!("{}{}", to_rid, &item_rid[rid.to_string().len()..]);
+                if copy_value(kv, &item_key(&item_rid), &item_key(&to_item_rid))? {
+                    items.push(to_item_rid);
+                    count += 1;
+                }
+            }
+            kv.set(&type_key(&to_key), TYPE_COLLECTION, None)?;
+            write_collection_order(kv, &to_key, &items)?;
+            if let Some(id) = kv.get(&item_id_key(&key))? {
+                kv.set(&item_id_key(&to_key), &id, None)?;
+            }
+        } else if copy_value(kv, &key, &to_key)? {
+            count += 1;
+        } else {
+            continue;
+        }
+        kv.set_add(&component_entities_key(to, &name), entity)?;
+        components.push(name);
+    }
+    write_collection_order(kv, &entity_components_key(to, entity), &components)?;
+    let owner = entity_owner(kv, from, entity)?;
+    claim_entity(kv, to, entity, owner.as_deref().unwrap_or(SYSTEM_OWNER))?;
 
-    ctx.kv().del_key(&type_key)?;
-    ctx.kv().set_remove(&ent_key, &entity_id(&tokens))?;
-    ctx.kv().del_key(&key)?;
+    let removal = match delete_entity(kv, from, entity)? {
+        Some(removal) => removal,
+        None => return Err(format!("entity {} left shard {} while it moved", entity, from).into()),
+    };
+    Ok(Some(EntityMigration { removal, count }))
+}
 
+/// Copies a component or collection item to a new key, along with its type and revision.
+/// Returns false, copying nothing, if the value has lapsed
+fn copy_value(kv: &impl KeyValue, key: &str, to_key: &str) -> codec::kv::Result<bool> {
+    let value = match kv.get(key)? {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    kv.set(to_key, &value, None)?;
+    let copies = [
+        (type_key(key), type_key(to_key)),
+        (revision_key(key), revision_key(to_key)),
+    ];
+    for (from, to) in &copies {
+        if let Some(v) = kv.get(from)? {
+            kv.set(to, &v, None)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Registers the JSON Schema that values of a component must conform to, replacing any
+/// previously registered schema
+pub(crate) fn put_schema(
+    kv: &impl KeyValue,
+    component: &str,
+    schema: &serde_json::Value,
+) -> codec::kv::Result<()> {
+    let key = Rid::Schema(component.to_string()).to_key();
+    kv.set(&key, &serde_json::to_string(schema)?, None)?;
     Ok(())
 }
 
-pub(crate) fn remove_component_from_collection(
-    ctx: &CapabilitiesContext,
-    rid: &str,
-    item_rid: &str,
-) -> Result<usize> {
-    let key = rid.replace('.', ":");
-    let item_key = item_rid.replace('.', ":");
-    let item_type_key = format!("{}:type", item_key);
-
-    ctx.log(&format!(
-        "Attempting to remove {} from key {}",
-        item_rid, key
-    ));
-    let idx = index_of(ctx, &key, item_rid)?;
-    let remcount = ctx.kv().list_del_item(&key, item_rid)?;
-    ctx.log(&format!("Removed {} items from list.", remcount));
-    ctx.kv().del_key(&item_key)?;
-    ctx.kv().del_key(&item_type_key)?;
+/// Retrieves the schema registered for a component, if there is one
+pub(crate) fn get_schema(
+    kv: &impl KeyValue,
+    component: &str,
+) -> codec::kv::Result<Option<serde_json::Value>> {
+    let key = Rid::Schema(component.to_string()).to_key();
+    match kv.get(&key)? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
 
-    Ok(idx)
+/// Removes the schema registered for a component, after which its values are unchecked.
+/// Returns a boolean indicating whether there was a schema to remove
+pub(crate) fn delete_schema(kv: &impl KeyValue, component: &str) -> codec::kv::Result<bool> {
+    let key = Rid::Schema(component.to_string()).to_key();
+    if !kv.exists(&key)? {
+        return Ok(false);
+    }
+    kv.del_key(&key)?;
+    Ok(true)
+}
+
+/// Registers a prefab: the bundle of component values, keyed by component name, that
+/// entities spawned from it start with. Replaces any previous prefab of the same name
+pub(crate) fn put_prefab(
+    kv: &impl KeyValue,
+    name: &str,
+    components: &serde_json::Map<String, serde_json::Value>,
+) -> codec::kv::Result<()> {
+    let key = Rid::Prefab(name.to_string()).to_key();
+    kv.set(&key, &serde_json::to_string(components)?, None)?;
+    Ok(())
+}
+
+/// Retrieves the bundle of component values of a prefab, if there is one by that name
+pub(crate) fn get_prefab(
+    kv: &impl KeyValue,
+    name: &str,
+) -> codec::kv::Result<Option<serde_json::Map<String, serde_json::Value>>> {
+    let key = Rid::Prefab(name.to_string()).to_key();
+    match kv.get(&key)? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Removes a prefab. Returns a boolean indicating whether there was a prefab to remove
+pub(crate) fn delete_prefab(kv: &impl KeyValue, name: &str) -> codec::kv::Result<bool> {
+    let key = Rid::Prefab(name.to_string()).to_key();
+    if !kv.exists(&key)? {
+        return Ok(false);
+    }
+    kv.del_key(&key)?;
+    Ok(true)
 }
 
-/// Extract the key-value store key for a single component from either the set or get RES protocol subject
-pub(crate) fn component_key(tokens: &[&str]) -> String {
-    tokens.join(":")
+/// Extract the shard, entity and component name from a component resource ID. Fails
+/// if the resource ID refers to an entity rather than one of its components
+pub(crate) fn component_parts(rid: &Rid) -> codec::kv::Result<(&str, &str, &str)> {
+    match rid {
+        Rid::Component {
+            shard,
+            entity,
+            component: Some(component),
+            ..
+        } => Ok((shard, entity, component)),
+        _ => Err(format!("not a component resource: {}", rid).into()),
+    }
 }
 
-/// Extract the key-value store key for the set of entities which have a given
-/// component associated with them.
-/// decs:{shard}:{component}:entities
-/// Subject looks like : call.decs.components.the_void.abc1234.position.set
-pub(crate) fn component_entities_key(tokens: &[&str]) -> String {
-    format!("decs:{}:{}:entities", tokens[2], tokens[4])
+/// The collection an item belongs to, if the resource ID refers to an item of a collection
+pub(crate) fn item_collection(rid: &Rid) -> Option<Rid> {
+    match rid {
+        Rid::Component {
+            shard,
+            entity,
+            component: Some(component),
+            item: Some(_),
+        } => Some(Rid::component(shard, entity, component)),
+        _ => None,
+    }
 }
 
-// decs.components.the_void.abc1234
-pub(crate) fn entity_id(tokens: &[&str]) -> String {
-    tokens[3].to_string()
+/// The item segment of a component resource ID, if it refers to an item of a collection
+fn rid_item(rid: &Rid) -> Option<&str> {
+    match rid {
+        Rid::Component { item, .. } => item.as_deref(),
+        _ => None,
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{component_entities_key, component_key};
+    use super::{
+        add_component_to_collection, claim_entity, component_counted, component_entities_key,
+        component_parts, component_revision, delete_component, delete_entity, entity_components,
+        entity_exists, entity_owner, entity_value_count, get_collection_rids, get_component,
+        insert_component_into_collection, migrate_entity, move_collection_item, put_component,
+        query_entities, remove_component_from_collection, shard_state, shard_users, sweep_expired,
+        Expired, COLLECTION_BUSY, ENTITY_EXISTS, INDEX_OUT_OF_RANGE, NO_SUCH_ITEM,
+        REVISION_CONFLICT, SYSTEM_OWNER,
+    };
+    use decscloud_common::gateway::Rid;
+    use decscloud_common::kv::{KeyValue, MemoryStore};
+    use decscloud_common::shard::ShardState;
+
+    fn rid(source: &str) -> Rid {
+        source.parse().unwrap()
+    }
+
+    /// Adds an item to a collection, applying the resulting add event to a client's copy
+    fn add_item(kv: &MemoryStore, collection: &Rid, client: &mut Vec<String>) -> String {
+        let added = add_component_to_collection(kv, collection, r#"{"mass":1}"#, None).unwrap();
+        client.insert(added.index, added.item_rid.clone());
+        added.item_rid
+    }
+
+    #[test]
+    fn test_collection_events_track_list() {
+        let kv = MemoryStore::new();
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client = vec![];
+        for _ in 0..4 {
+            add_item(&kv, &cargo, &mut client);
+            assert_eq!(client, get_collection_rids(&kv, &cargo).unwrap());
+        }
+        for pick in [1, 2, 0, 0] {
+            let item = client[pick].clone();
+            let idx = remove_component_from_collection(&kv, &cargo, &item, None).unwrap();
+            assert_eq!(client.remove(idx), item);
+            assert_eq!(client, get_collection_rids(&kv, &cargo).unwrap());
+            assert!(!kv.exists(&rid(&item).to_key()).unwrap());
+        }
+        assert!(client.is_empty());
+    }
+
+    #[test]
+    fn test_insert_and_move_track_list() {
+        let kv = MemoryStore::new();
+        let waypoints = rid("decs.components.the_void.ship1.waypoints");
+        let mut client = vec![];
+        for _ in 0..3 {
+            add_item(&kv, &waypoints, &mut client);
+        }
+        for idx in [3, 1, 0] {
+            let added =
+                insert_component_into_collection(&kv, &waypoints, r#"{"x":1}"#, idx, None).unwrap();
+            assert_eq!(added.index, idx);
+            client.insert(idx, added.item_rid);
+            assert_eq!(client, get_collection_rids(&kv, &waypoints).unwrap());
+        }
+        for (pick, to) in [(0, 5), (5, 0), (2, 3), (4, 1), (3, 3)] {
+            let item = client[pick].clone();
+            let from = move_collection_item(&kv, &waypoints, &item, to).unwrap();
+            assert_eq!(from, pick);
+            client.remove(from);
+            client.insert(to, item);
+            assert_eq!(client, get_collection_rids(&kv, &waypoints).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_insert_and_move_out_of_range() {
+        let kv = MemoryStore::new();
+        let waypoints = rid("decs.components.the_void.ship1.waypoints");
+        let mut client = vec![];
+        let item = add_item(&kv, &waypoints, &mut client);
+        add_item(&kv, &waypoints, &mut client);
+
+        let err =
+            insert_component_into_collection(&kv, &waypoints, r#"{"x":1}"#, 3, None).unwrap_err();
+        assert_eq!(err.to_string(), INDEX_OUT_OF_RANGE);
+        let err = move_collection_item(&kv, &waypoints, &item, 2).unwrap_err();
+        assert_eq!(err.to_string(), INDEX_OUT_OF_RANGE);
+        let missing = "decs.components.the_void.ship1.waypoints.99";
+        let err = move_collection_item(&kv, &waypoints, missing, 0).unwrap_err();
+        assert_eq!(err.to_string(), NO_SUCH_ITEM);
+        assert_eq!(client, get_collection_rids(&kv, &waypoints).unwrap());
+    }
+
+    #[test]
+    fn test_collection_changes_wait_for_lock() {
+        let kv = MemoryStore::new();
+        let waypoints = rid("decs.components.the_void.ship1.waypoints");
+        let mut client = vec![];
+        let item = add_item(&kv, &waypoints, &mut client);
+        add_item(&kv, &waypoints, &mut client);
+        // a failed change releases the lock as well
+        assert!(move_collection_item(&kv, &waypoints, &item, 5).is_err());
+
+        // another request is part way through a reorder
+        let lock = "decs:components:the_void:ship1:waypoints:lock";
+        kv.atomic_add(lock, 1).unwrap();
+        let busy = [
+            move_collection_item(&kv, &waypoints, &item, 0).unwrap_err(),
+            add_component_to_collection(&kv, &waypoints, "{}", None).unwrap_err(),
+            insert_component_into_collection(&kv, &waypoints, "{}", 1, None).unwrap_err(),
+            remove_component_from_collection(&kv, &waypoints, &item, None).unwrap_err(),
+        ];
+        for err in &busy {
+            assert_eq!(err.to_string(), COLLECTION_BUSY);
+        }
+        assert_eq!(client, get_collection_rids(&kv, &waypoints).unwrap());
+
+        kv.del_key(lock).unwrap();
+        move_collection_item(&kv, &waypoints, &item, 0).unwrap();
+        assert!(!kv.exists(lock).unwrap());
+    }
+
+    #[test]
+    fn test_remove_item_not_in_collection() {
+        let kv = MemoryStore::new();
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client = vec![];
+        let item = add_item(&kv, &cargo, &mut client);
+        add_item(&kv, &cargo, &mut client);
+
+        let missing = "decs.components.the_void.ship1.cargo.99";
+        let err = remove_component_from_collection(&kv, &cargo, missing, None).unwrap_err();
+        assert_eq!(err.to_string(), NO_SUCH_ITEM);
+
+        remove_component_from_collection(&kv, &cargo, &item, None).unwrap();
+        let err = remove_component_from_collection(&kv, &cargo, &item, None).unwrap_err();
+        assert_eq!(err.to_string(), NO_SUCH_ITEM);
+        assert_eq!(get_collection_rids(&kv, &cargo).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_remove_item_revision_conflict() {
+        let kv = MemoryStore::new();
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client = vec![];
+        let item = add_item(&kv, &cargo, &mut client);
+
+        let err = remove_component_from_collection(&kv, &cargo, &item, Some(5)).unwrap_err();
+        assert_eq!(err.to_string(), REVISION_CONFLICT);
+        assert_eq!(client, get_collection_rids(&kv, &cargo).unwrap());
+        assert!(kv.exists(&rid(&item).to_key()).unwrap());
+
+        // a new item is at revision 1
+        remove_component_from_collection(&kv, &cargo, &item, Some(1)).unwrap();
+        assert!(get_collection_rids(&kv, &cargo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_component_revision_conflict() {
+        let kv = MemoryStore::new();
+        let position = rid("decs.components.the_void.ship1.position");
+        put_component(&kv, &position, r#"{"x":1}"#, None, None).unwrap();
+
+        let err = put_component(&kv, &position, r#"{"x":2}"#, Some(2), None).unwrap_err();
+        assert_eq!(err.to_string(), REVISION_CONFLICT);
+        assert_eq!(get_component(&kv, &position).unwrap(), r#"{"x":1}"#);
+        assert_eq!(component_revision(&kv, &position).unwrap(), 1);
+
+        let write = put_component(&kv, &position, r#"{"x":2}"#, Some(1), None).unwrap();
+        assert_eq!(write.revision, 2);
+        assert_eq!(get_component(&kv, &position).unwrap(), r#"{"x":2}"#);
+    }
+
+    #[test]
+    fn test_add_skips_stale_item_ids() {
+        let kv = MemoryStore::new();
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client = vec![];
+        let first = add_item(&kv, &cargo, &mut client);
+        kv.del_key("decs:components:the_void:ship1:cargo:id")
+            .unwrap();
+
+        let second = add_item(&kv, &cargo, &mut client);
+        assert_ne!(first, second);
+        assert_eq!(client, vec![second, first]);
+        assert_eq!(client, get_collection_rids(&kv, &cargo).unwrap());
+    }
+
+    #[test]
+    fn test_entity_collection_events_track_list() {
+        let kv = MemoryStore::new();
+        let position = rid("decs.components.the_void.ship1.position");
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client: Vec<String> = vec![];
+
+        let write = put_component(&kv, &position, r#"{"x":1}"#, None, None).unwrap();
+        client.insert(write.entity_index.unwrap(), "position".to_string());
+        let write = put_component(&kv, &position, r#"{"x":2}"#, None, None).unwrap();
+        assert!(write.entity_index.is_none());
+        assert_eq!(write.revision, 2);
+
+        let added = add_component_to_collection(&kv, &cargo, "{}", None).unwrap();
+        client.insert(added.entity_index.unwrap(), "cargo".to_string());
+        let added = add_component_to_collection(&kv, &cargo, "{}", None).unwrap();
+        assert!(added.entity_index.is_none());
+        assert_eq!(client, entity_components(&kv, "the_void", "ship1").unwrap());
+
+        let idx = delete_component(&kv, &position, None).unwrap().unwrap();
+        assert_eq!(client.remove(idx), "position");
+        assert_eq!(client, entity_components(&kv, "the_void", "ship1").unwrap());
+        assert!(delete_component(&kv, &position, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_sweep_expired_items() {
+        let kv = MemoryStore::new();
+        let cargo = rid("decs.components.the_void.ship1.cargo");
+        let mut client = vec![];
+        add_item(&kv, &cargo, &mut client);
+        let added = add_component_to_collection(&kv, &cargo, "{}", Some(30)).unwrap();
+        client.insert(added.index, added.item_rid.clone());
+        add_item(&kv, &cargo, &mut client);
+
+        assert!(sweep_expired(&kv, "the_void").unwrap().is_empty());
+        // the store drops the value of the item once its time to live has passed
+        kv.del_key(&rid(&added.item_rid).to_key()).unwrap();
+        let expired = sweep_expired(&kv, "the_void").unwrap();
+        assert_eq!(expired.len(), 1);
+        match &expired[0] {
+            Expired::Item {
+                rid,
+                collection,
+                index: Some(idx),
+            } => {
+                assert_eq!(rid.to_string(), added.item_rid);
+                assert_eq!(collection, &cargo);
+                assert_eq!(client.remove(*idx), added.item_rid);
+            }
+            _ => panic!("expected the item to expire"),
+        }
+        assert_eq!(client, get_collection_rids(&kv, &cargo).unwrap());
+        assert!(sweep_expired(&kv, "the_void").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_query_entities() {
+        let kv = MemoryStore::new();
+        let put = |entity: &str, component: &str| {
+            let rid = Rid::component("the_void", entity, component);
+            put_component(&kv, &rid, "{}", None, None).unwrap();
+            claim_entity(&kv, "the_void", entity, SYSTEM_OWNER).unwrap();
+        };
+        put("b", "position");
+        put("b", "velocity");
+        put("a", "position");
+        put("a", "velocity");
+        put("a", "dead");
+        put("c", "position");
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            query_entities(&kv, "the_void", &[], &[]).unwrap(),
+            names(&["a", "b", "c"])
+        );
+        assert_eq!(
+            query_entities(&kv, "the_void", &names(&["position", "velocity"]), &[]).unwrap(),
+            names(&["a", "b"])
+        );
+        assert_eq!(
+            query_entities(
+                &kv,
+                "the_void",
+                &names(&["position", "velocity"]),
+                &names(&["dead"])
+            )
+            .unwrap(),
+            names(&["b"])
+        );
+        assert_eq!(
+            query_entities(&kv, "the_void", &[], &names(&["velocity"])).unwrap(),
+            names(&["c"])
+        );
+    }
 
     #[test]
     fn test_entities_key_extraction() {
-        let subject = "decs.components.the_void.abc1234.position";
-        let tokens: Vec<&str> = subject.split('.').collect();
+        let rid: Rid = "decs.components.the_void.abc1234.position".parse().unwrap();
+        let (shard, _, component) = component_parts(&rid).unwrap();
         assert_eq!(
             "decs:the_void:position:entities",
-            component_entities_key(&tokens)
+            component_entities_key(shard, component)
         )
     }
 
     #[test]
     fn test_key_extraction() {
-        let subject1 = "decs.components.the_void.abc1234.position";
-        let subject3 = "decs.components.the_void.abc1234.radar_contacts";
-        let tokens: Vec<&str> = subject1.split('.').collect();
-        let tokens3: Vec<&str> = subject3.split('.').collect();
+        let rid1: Rid = "decs.components.the_void.abc1234.position".parse().unwrap();
+        let rid3: Rid = "decs.components.the_void.abc1234.radar_contacts"
+            .parse()
+            .unwrap();
+
+        assert_eq!("decs:components:the_void:abc1234:position", rid1.to_key());
 
         assert_eq!(
-            "decs:components:the_void:abc1234:position",
-            component_key(&tokens)
+            "decs:components:the_void:abc1234:radar_contacts",
+            rid3.to_key()
         );
+    }
+
+    #[test]
+    fn test_entity_rid_has_no_component_parts() {
+        let rid: Rid = "decs.components.the_void.abc1234".parse().unwrap();
+        assert!(component_parts(&rid).is_err());
+    }
+
+    #[test]
+    fn test_delete_entity_leaves_shard_users() {
+        let kv = MemoryStore::new();
+        for ship in &["ship1", "ship2"] {
+            let position = rid(&format!("decs.components.zone1.{}.position", ship));
+            put_component(&kv, &position, r#"{"x":1}"#, None, None).unwrap();
+            claim_entity(&kv, "zone1", ship, "bob").unwrap();
+        }
+
+        delete_entity(&kv, "zone1", "ship1").unwrap().unwrap();
+        assert_eq!(shard_users(&kv, "zone1").unwrap(), vec!["bob".to_string()]);
+        delete_entity(&kv, "zone1", "ship2").unwrap().unwrap();
+        assert!(shard_users(&kv, "zone1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_entity() {
+        let kv = MemoryStore::new();
+        let position = rid("decs.components.zone1.ship1.position");
+        let cargo = rid("decs.components.zone1.ship1.cargo");
+        put_component(&kv, &position, r#"{"x":1}"#, None, None).unwrap();
+        put_component(&kv, &position, r#"{"x":2}"#, None, None).unwrap();
+        let mut items = vec![];
+        for _ in 0..3 {
+            add_item(&kv, &cargo, &mut items);
+        }
+        claim_entity(&kv, "zone1", "ship1", "bob").unwrap();
+        assert_eq!(entity_value_count(&kv, "zone1", "ship1").unwrap(), 4);
+        let names = entity_components(&kv, "zone1", "ship1").unwrap();
 
+        let migration = migrate_entity(&kv, "zone1", "ship1", "zone2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(migration.count, 4);
+        assert_eq!(migration.removal.count, 4);
+        assert!(migration
+            .removal
+            .deleted
+            .contains(&"decs.components.zone1.ship1".to_string()));
+
+        let moved = rid("decs.components.zone2.ship1.position");
+        assert_eq!(get_component(&kv, &moved).unwrap(), r#"{"x":2}"#);
+        assert_eq!(component_revision(&kv, &moved).unwrap(), 2);
+        let moved_items: Vec<String> = items.iter().map(|i| i.replace("zone1", "zone2")).collect();
         assert_eq!(
-            "decs:components:the_void:abc1234:radar_contacts",
-            component_key(&tokens3)
+            get_collection_rids(&kv, &rid("decs.components.zone2.ship1.cargo")).unwrap(),
+            moved_items
+        );
+        for item in &moved_items {
+            assert_eq!(get_component(&kv, &rid(item)).unwrap(), r#"{"mass":1}"#);
+        }
+        assert_eq!(entity_components(&kv, "zone2", "ship1").unwrap(), names);
+        assert_eq!(
+            entity_owner(&kv, "zone2", "ship1").unwrap().as_deref(),
+            Some("bob")
+        );
+        assert_eq!(
+            query_entities(&kv, "zone2", &["cargo".to_string()], &[]).unwrap(),
+            vec!["ship1".to_string()]
+        );
+
+        assert!(!entity_exists(&kv, "zone1", "ship1").unwrap());
+        assert!(query_entities(&kv, "zone1", &[], &[]).unwrap().is_empty());
+        // bob owns nothing else in the old shard, so nothing at all is left behind
+        assert!(shard_users(&kv, "zone1").unwrap().is_empty());
+        assert_eq!(shard_users(&kv, "zone2").unwrap(), vec!["bob".to_string()]);
+        assert!(!kv.keys().iter().any(|k| k.contains(":zone1:")));
+
+        // items added after the move don't reuse IDs
+        let mut moved_items = moved_items;
+        let added = add_item(
+            &kv,
+            &rid("decs.components.zone2.ship1.cargo"),
+            &mut moved_items,
         );
+        assert!(!items.contains(&added.replace("zone2", "zone1")));
+    }
+
+    #[test]
+    fn test_migrate_entity_refused() {
+        let kv = MemoryStore::new();
+        let put = |shard: &str| {
+            let rid = Rid::component(shard, "ship1", "position");
+            put_component(&kv, &rid, "{}", None, None).unwrap();
+            claim_entity(&kv, shard, "ship1", SYSTEM_OWNER).unwrap();
+        };
+        put("zone1");
+        put("zone2");
+
+        let err = migrate_entity(&kv, "zone1", "ship1", "zone2")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), ENTITY_EXISTS);
+        assert!(entity_exists(&kv, "zone1", "ship1").unwrap());
+        assert!(migrate_entity(&kv, "zone1", "ship2", "zone3")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_component_counted() {
+        let kv = MemoryStore::new();
+        let position = rid("decs.components.zone1.ship1.position");
+        assert!(!component_counted(&kv, &position).unwrap());
+        put_component(&kv, &position, "{}", None, Some(30)).unwrap();
+        assert!(component_counted(&kv, &position).unwrap());
+
+        // lapsed, but not yet swept up
+        kv.del_key(&position.to_key()).unwrap();
+        assert!(component_counted(&kv, &position).unwrap());
+        delete_component(&kv, &position, None).unwrap();
+        assert!(!component_counted(&kv, &position).unwrap());
+    }
+
+    #[test]
+    fn test_shard_state() {
+        let kv = MemoryStore::new();
+        assert_eq!(shard_state(&kv, "zone1").unwrap(), ShardState::Running);
+        kv.set(
+            "decs:shard:zone1",
+            r#"{"name":"zone1","capacity":10,"state":"read-only"}"#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(shard_state(&kv, "zone1").unwrap(), ShardState::ReadOnly);
     }
 }
@@ -14,6 +14,207 @@ pub mod gateway {
         pub rid: String,
     }
 
+    /// A parsed dECS Cloud resource ID. Every resource exposed over the RES protocol
+    /// by the dECS Cloud managers has one of these shapes
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Rid {
+        /// decs.components.{shard}, through which entities are spawned in a shard
+        ShardComponents(String),
+        /// decs.components.{shard}.{entity}[.{component}[.{item}]]
+        Component {
+            shard: String,
+            entity: String,
+            component: Option<String>,
+            item: Option<String>,
+        },
+        /// decs.entities.{shard}, the (queryable) collection of entities in a shard
+        Entities(String),
+        /// decs.schemas.{component}
+        Schema(String),
+        /// decs.prefabs.{name}, a template bundle of components to spawn entities from
+        Prefab(String),
+        /// decs.shard.{name}
+        Shard(String),
+        /// decs.shards
+        Shards,
+        /// decs.system.{name}
+        System(String),
+        /// decs.systems
+        Systems,
+        /// decs.user.{id}
+        User(String),
+        /// decs.users
+        Users,
+    }
+
+    /// Indicates why a string could not be parsed into a resource ID
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum RidError {
+        /// The resource ID does not begin with the `decs.` namespace
+        BadNamespace(String),
+        /// The resource type (e.g. `shard` in `decs.shard.x`) is not known
+        UnknownResource(String),
+        /// The resource ID has too few or too many segments for its type
+        SegmentCount(String),
+        /// A segment is empty or contains a character reserved by the message broker
+        InvalidSegment(String),
+    }
+
+    impl std::fmt::Display for RidError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                RidError::BadNamespace(rid) => {
+                    write!(f, "resource ID is not in the decs namespace: {}", rid)
+                }
+                RidError::UnknownResource(res) => write!(f, "unknown resource type: {}", res),
+                RidError::SegmentCount(rid) => {
+                    write!(f, "wrong number of segments in resource ID: {}", rid)
+                }
+                RidError::InvalidSegment(seg) => {
+                    write!(f, "invalid resource ID segment: '{}'", seg)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for RidError {}
+
+    impl Rid {
+        /// Convenience constructor for a single component (model or collection) of an entity
+        pub fn component(shard: &str, entity: &str, component: &str) -> Rid {
+            Rid::Component {
+                shard: shard.to_string(),
+                entity: entity.to_string(),
+                component: Some(component.to_string()),
+                item: None,
+            }
+        }
+
+        /// The shard in which this resource lives, if it is scoped to a shard
+        pub fn shard(&self) -> Option<&str> {
+            match self {
+                Rid::Component { shard, .. } => Some(shard),
+                Rid::ShardComponents(shard) => Some(shard),
+                Rid::Entities(shard) => Some(shard),
+                Rid::Shard(name) => Some(name),
+                _ => None,
+            }
+        }
+
+        /// The entity to which this resource belongs, if it is an entity or one of its components
+        pub fn entity(&self) -> Option<&str> {
+            match self {
+                Rid::Component { entity, .. } => Some(entity),
+                _ => None,
+            }
+        }
+
+        /// The entity to which this resource belongs, as a resource of its own
+        pub fn entity_rid(&self) -> Option<Rid> {
+            match self {
+                Rid::Component { shard, entity, .. } => Some(Rid::Component {
+                    shard: shard.clone(),
+                    entity: entity.clone(),
+                    component: None,
+                    item: None,
+                }),
+                _ => None,
+            }
+        }
+
+        /// The key under which this resource is kept in the key-value store
+        pub fn to_key(&self) -> String {
+            self.to_string().replace('.', ":")
+        }
+    }
+
+    /// Segments may not hold wildcards, queries or whitespace, nor `:`, which `to_key` uses
+    /// in place of `.` and so would let two resource IDs share a key
+    fn valid_segment(seg: &str) -> Result<String, RidError> {
+        if seg.is_empty()
+            || seg
+                .chars()
+                .any(|c| c == '*' || c == '>' || c == '?' || c == ':' || c.is_whitespace())
+        {
+            Err(RidError::InvalidSegment(seg.to_string()))
+        } else {
+            Ok(seg.to_string())
+        }
+    }
+
+    impl std::str::FromStr for Rid {
+        type Err = RidError;
+
+        fn from_str(source: &str) -> Result<Self, Self::Err> {
+            let tokens: Vec<&str> = source.split('.').collect();
+            if tokens[0] != "decs" || tokens.len() < 2 {
+                return Err(RidError::BadNamespace(source.to_string()));
+            }
+            let args = &tokens[2..];
+            let count_err = || RidError::SegmentCount(source.to_string());
+            match tokens[1] {
+                "components" if args.len() == 1 => {
+                    Ok(Rid::ShardComponents(valid_segment(args[0])?))
+                }
+                "components" => {
+                    if args.len() < 2 || args.len() > 4 {
+                        return Err(count_err());
+                    }
+                    Ok(Rid::Component {
+                        shard: valid_segment(args[0])?,
+                        entity: valid_segment(args[1])?,
+                        component: args.get(2).map(|s| valid_segment(s)).transpose()?,
+                        item: args.get(3).map(|s| valid_segment(s)).transpose()?,
+                    })
+                }
+                "entities" if args.len() == 1 => Ok(Rid::Entities(valid_segment(args[0])?)),
+                "schemas" if args.len() == 1 => Ok(Rid::Schema(valid_segment(args[0])?)),
+                "prefabs" if args.len() == 1 => Ok(Rid::Prefab(valid_segment(args[0])?)),
+                "shard" if args.len() == 1 => Ok(Rid::Shard(valid_segment(args[0])?)),
+                "system" if args.len() == 1 => Ok(Rid::System(valid_segment(args[0])?)),
+                "user" if args.len() == 1 => Ok(Rid::User(valid_segment(args[0])?)),
+                "shards" if args.is_empty() => Ok(Rid::Shards),
+                "systems" if args.is_empty() => Ok(Rid::Systems),
+                "users" if args.is_empty() => Ok(Rid::Users),
+                "entities" | "schemas" | "prefabs" | "shard" | "system" | "user" | "shards"
+                | "systems" | "users" => Err(count_err()),
+                other => Err(RidError::UnknownResource(other.to_string())),
+            }
+        }
+    }
+
+    impl std::fmt::Display for Rid {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Rid::Component {
+                    shard,
+                    entity,
+                    component,
+                    item,
+                } => {
+                    write!(f, "decs.components.{}.{}", shard, entity)?;
+                    if let Some(c) = component {
+                        write!(f, ".{}", c)?;
+                    }
+                    if let Some(i) = item {
+                        write!(f, ".{}", i)?;
+                    }
+                    Ok(())
+                }
+                Rid::ShardComponents(shard) => write!(f, "decs.components.{}", shard),
+                Rid::Entities(shard) => write!(f, "decs.entities.{}", shard),
+                Rid::Schema(component) => write!(f, "decs.schemas.{}", component),
+                Rid::Prefab(name) => write!(f, "decs.prefabs.{}", name),
+                Rid::Shard(name) => write!(f, "decs.shard.{}", name),
+                Rid::Shards => write!(f, "decs.shards"),
+                Rid::System(name) => write!(f, "decs.system.{}", name),
+                Rid::Systems => write!(f, "decs.systems"),
+                Rid::User(id) => write!(f, "decs.user.{}", id),
+                Rid::Users => write!(f, "decs.users"),
+            }
+        }
+    }
+
     /// Generates a positive RES protocol result containing a model
     pub fn model_result(model: serde_json::Value) -> serde_json::Value {
         json!({
@@ -23,81 +224,448 @@ pub mod gateway {
         })
     }
 
-    /// Generates a RES protocol error indicating not found (e.g. HTTP 404)
-    pub fn error_not_found(msg: &str) -> serde_json::Value {
+    /// Generates a positive RES protocol result containing a collection of resource references
+    pub fn collection_result<I, S>(rids: I) -> serde_json::Value
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        let refs: Vec<_> = rids
+            .into_iter()
+            .map(|rid| ResourceIdentifier {
+                rid: rid.to_string(),
+            })
+            .collect();
         json!({
-            "error": {
-                "code": "system.notFound",
-                "message": msg
+            "result": {
+                "collection": refs
             }
         })
     }
 
-    /// Generates a RES protocol error indicating invalid parameters (e.g. HTTP bad request)
-    pub fn error_invalid_params(msg: &str) -> serde_json::Value {
+    /// Generates a positive RES protocol result for a query collection. The normalized
+    /// query is echoed back so RESgate can match it against future query events
+    pub fn query_collection_result<I, S>(rids: I, query: &str) -> serde_json::Value
+    where
+        I: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        let mut result = collection_result(rids);
+        result["result"]["query"] = json!(query);
+        result
+    }
+
+    /// Generates a positive RES protocol result for a query model
+    pub fn query_model_result(model: serde_json::Value, query: &str) -> serde_json::Value {
+        json!({
+            "result" :{
+                "model" : model,
+                "query": query
+            }
+        })
+    }
+
+    /// Generates a positive RES protocol result referring to a single resource, e.g. the
+    /// resource created by a `new` call
+    pub fn resource_result(rid: &str) -> serde_json::Value {
+        json!({ "result": ResourceIdentifier { rid: rid.to_string() } })
+    }
+
+    /// Generates a RES protocol access response. `call` is either a comma-separated list of
+    /// callable methods, `*` for all methods, or `None` for no methods
+    pub fn access_result(get: bool, call: Option<&str>) -> serde_json::Value {
         json!({
-            "error": {
-                "code": "system.invalidParams",
-                "message": msg
+            "result" : {
+                "get" : get,
+                "call" : call
             }
         })
     }
 
+    /// The error codes defined by the RES protocol, plus any custom (application) codes
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ErrorCode {
+        NotFound,
+        InvalidParams,
+        InvalidQuery,
+        InternalError,
+        MethodNotFound,
+        AccessDenied,
+        Timeout,
+        /// The request conflicts with the current state of the resource, e.g. a stale
+        /// revision or a duplicate unique value
+        Conflict,
+        /// The shard has no room left for more components
+        ShardFull,
+        /// The shard's state doesn't allow the request, e.g. a write to a read-only shard
+        ShardClosed,
+        /// An application-specific error code, e.g. `decs.zoneClosed`
+        Custom(String),
+    }
+
+    impl ErrorCode {
+        /// The code as it appears on the wire
+        pub fn as_str(&self) -> &str {
+            match self {
+                ErrorCode::NotFound => "system.notFound",
+                ErrorCode::InvalidParams => "system.invalidParams",
+                ErrorCode::InvalidQuery => "system.invalidQuery",
+                ErrorCode::InternalError => "system.internalError",
+                ErrorCode::MethodNotFound => "system.methodNotFound",
+                ErrorCode::AccessDenied => "system.accessDenied",
+                ErrorCode::Timeout => "system.timeout",
+                ErrorCode::Conflict => "decs.conflict",
+                ErrorCode::ShardFull => "decs.shardFull",
+                ErrorCode::ShardClosed => "decs.shardClosed",
+                ErrorCode::Custom(code) => code,
+            }
+        }
+    }
+
+    impl std::fmt::Display for ErrorCode {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}", self.as_str())
+        }
+    }
+
+    fn error_object(code: &ErrorCode, msg: &str) -> serde_json::Value {
+        json!({
+            "code": code.as_str(),
+            "message": msg
+        })
+    }
+
+    /// Generates a RES protocol error response with the given code
+    pub fn error_response(code: ErrorCode, msg: &str) -> serde_json::Value {
+        json!({ "error": error_object(&code, msg) })
+    }
+
+    /// Generates a RES protocol error response carrying additional structured data
+    pub fn error_response_with_data(
+        code: ErrorCode,
+        msg: &str,
+        data: serde_json::Value,
+    ) -> serde_json::Value {
+        let mut err = error_object(&code, msg);
+        err["data"] = data;
+        json!({ "error": err })
+    }
+
+    /// Generates a RES protocol error indicating not found (e.g. HTTP 404)
+    pub fn error_not_found(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::NotFound, msg)
+    }
+
+    /// Generates a RES protocol error indicating invalid parameters (e.g. HTTP bad request)
+    pub fn error_invalid_params(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::InvalidParams, msg)
+    }
+
+    /// Generates a RES protocol error indicating a malformed query string
+    pub fn error_invalid_query(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::InvalidQuery, msg)
+    }
+
+    /// Generates a RES protocol error indicating the caller may not perform the request (e.g. HTTP 403)
+    pub fn error_access_denied(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::AccessDenied, msg)
+    }
+
+    /// Generates a RES protocol error indicating a failure inside the service (e.g. HTTP 500)
+    pub fn error_internal(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::InternalError, msg)
+    }
+
+    /// Generates a RES protocol error indicating the called method does not exist on the resource
+    pub fn error_method_not_found(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::MethodNotFound, msg)
+    }
+
+    /// Generates a RES protocol error indicating the request timed out
+    pub fn error_timeout(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::Timeout, msg)
+    }
+
+    /// Generates a RES protocol error indicating a conflict with the resource's current state
+    pub fn error_conflict(msg: &str) -> serde_json::Value {
+        error_response(ErrorCode::Conflict, msg)
+    }
+
+    /// Generates a RES protocol error indicating a shard is at capacity, with the shard's
+    /// capacity and current component count as data
+    pub fn error_shard_full(msg: &str, capacity: u32, current: u32) -> serde_json::Value {
+        error_response_with_data(
+            ErrorCode::ShardFull,
+            msg,
+            json!({ "capacity": capacity, "current": current }),
+        )
+    }
+
+    /// Generates a RES protocol error indicating a shard's state doesn't allow the request,
+    /// with the shard's state as data
+    pub fn error_shard_closed(msg: &str, state: crate::shard::ShardState) -> serde_json::Value {
+        error_response_with_data(ErrorCode::ShardClosed, msg, json!({ "state": state }))
+    }
+
     /// Generates a RES protocol success response with no payload
     pub fn success_response() -> serde_json::Value {
         json!({ "result": null })
     }
 
-    /// Represents the intent of a RES protocol request as described by a message broker subject
-    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    /// Generates a RES protocol success response for a call method returning a value
+    pub fn call_result(value: serde_json::Value) -> serde_json::Value {
+        json!({ "result": value })
+    }
+
+    /// Merges a partial model into an existing one, the way a RES `set` call is meant to be
+    /// applied: values in `patch` replace those in `model`, and a `null` value deletes the
+    /// property. Returns the values for a change event describing what actually changed,
+    /// with deleted properties marked by `{"action": "delete"}`; it is empty if nothing changed
+    pub fn merge_model(
+        model: &mut serde_json::Map<String, serde_json::Value>,
+        patch: &serde_json::Map<String, serde_json::Value>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut changed = serde_json::Map::new();
+        for (key, value) in patch {
+            if value.is_null() {
+                if model.remove(key).is_some() {
+                    changed.insert(key.clone(), json!({ "action": "delete" }));
+                }
+            } else if model.get(key) != Some(value) {
+                model.insert(key.clone(), value.clone());
+                changed.insert(key.clone(), value.clone());
+            }
+        }
+        changed
+    }
+
+    /// A RES protocol event, ready to be published on its subject
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ResEvent {
+        pub subject: String,
+        pub payload: Option<serde_json::Value>,
+    }
+
+    impl ResEvent {
+        /// The message body for the event. Events without a payload have an empty body
+        pub fn body(&self) -> Vec<u8> {
+            match self.payload {
+                Some(ref p) => serde_json::to_vec(p).unwrap_or_default(),
+                None => vec![],
+            }
+        }
+
+        /// A model change event, containing only the changed values
+        pub fn change(rid: &str, values: serde_json::Value) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.change", rid),
+                payload: Some(json!({ "values": values })),
+            }
+        }
+
+        /// A collection add event for a resource reference inserted at `idx`
+        pub fn add(rid: &str, item_rid: &str, idx: usize) -> ResEvent {
+            ResEvent::add_value(
+                rid,
+                json!(ResourceIdentifier {
+                    rid: item_rid.to_string()
+                }),
+                idx,
+            )
+        }
+
+        /// A collection add event for an arbitrary value inserted at `idx`
+        pub fn add_value(rid: &str, value: serde_json::Value, idx: usize) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.add", rid),
+                payload: Some(json!({
+                    "value": value,
+                    "idx": idx
+                })),
+            }
+        }
+
+        /// A collection remove event for the item at `idx`
+        pub fn remove(rid: &str, idx: usize) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.remove", rid),
+                payload: Some(json!({ "idx": idx })),
+            }
+        }
+
+        /// Tells RESgate the resource has been deleted and will not come back
+        pub fn delete(rid: &str) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.delete", rid),
+                payload: None,
+            }
+        }
+
+        /// Tells RESgate that the results of queries on the resource may have changed. RESgate
+        /// sends each cached query it holds as a request to `subject` to learn the new results
+        pub fn query(rid: &str, subject: &str) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.query", rid),
+                payload: Some(json!({ "subject": subject })),
+            }
+        }
+
+        /// Tells RESgate to discard cached access for the resource and ask again
+        pub fn reaccess(rid: &str) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.reaccess", rid),
+                payload: None,
+            }
+        }
+
+        /// Forces all clients to unsubscribe from the resource, e.g. because it was deleted
+        pub fn unsubscribe(rid: &str, code: ErrorCode, msg: &str) -> ResEvent {
+            ResEvent {
+                subject: format!("event.{}.unsubscribe", rid),
+                payload: Some(json!({ "reason": error_object(&code, msg) })),
+            }
+        }
+
+        /// Sets (or, with a null token, clears) the access token of a client connection
+        pub fn connection_token(cid: &str, token: serde_json::Value) -> ResEvent {
+            ResEvent {
+                subject: format!("conn.{}.token", cid),
+                payload: Some(json!({ "token": token })),
+            }
+        }
+
+        /// Tells RESgate to refetch the matching resources and re-check the matching access.
+        /// Patterns may use the `*` and `>` wildcards
+        pub fn system_reset(resources: &[&str], access: &[&str]) -> ResEvent {
+            ResEvent {
+                subject: "system.reset".to_string(),
+                payload: Some(json!({
+                    "resources": resources,
+                    "access": access
+                })),
+            }
+        }
+    }
+
+    /// Represents the intent of a RES protocol request as described by a message broker subject.
+    /// Request subjects take one of the following forms, as per the RES service protocol:
+    /// ```text
+    /// get.<rid>[?<query>]
+    /// access.<rid>
+    /// call.<rid>.<method>
+    /// auth.<rid>.<method>
+    /// ```
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
     pub enum ResProtocolRequest {
         Get(String),
+        /// A get request for a query resource: the resource ID and the query string
+        Query(String, String),
         Add(String),
         New(String),
         Set(String),
         Delete(String),
+        Remove(String),
         Access(String),
         Call(String, String),
+        /// An authentication request: the resource ID and the auth method
+        Auth(String, String),
         Unknown,
     }
 
-    impl ToString for ResProtocolRequest {
-        fn to_string(&self) -> String {
+    impl ResProtocolRequest {
+        /// The resource ID targeted by the request, without any query string
+        pub fn rid(&self) -> Option<&str> {
+            match self {
+                ResProtocolRequest::Get(resid)
+                | ResProtocolRequest::Query(resid, _)
+                | ResProtocolRequest::Add(resid)
+                | ResProtocolRequest::New(resid)
+                | ResProtocolRequest::Set(resid)
+                | ResProtocolRequest::Delete(resid)
+                | ResProtocolRequest::Remove(resid)
+                | ResProtocolRequest::Access(resid)
+                | ResProtocolRequest::Call(resid, _)
+                | ResProtocolRequest::Auth(resid, _) => Some(resid),
+                ResProtocolRequest::Unknown => None,
+            }
+        }
+    }
+
+    impl std::fmt::Display for ResProtocolRequest {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             match self {
-                ResProtocolRequest::Get(resid) => format!("get.{}", resid),
-                ResProtocolRequest::Add(resid) => format!("call.{}.add", resid),
-                ResProtocolRequest::New(resid) => format!("call.{}.new", resid),
-                ResProtocolRequest::Set(resid) => format!("call.{}.set", resid),
-                ResProtocolRequest::Delete(resid) => format!("call.{}.delete", resid),
-                ResProtocolRequest::Access(resid) => format!("access.{}", resid),
-                ResProtocolRequest::Call(resid, method) => format!("call.{}.{}", resid, method),
-                ResProtocolRequest::Unknown => "??".to_string(),
+                ResProtocolRequest::Get(resid) => write!(f, "get.{}", resid),
+                ResProtocolRequest::Query(resid, query) => write!(f, "get.{}?{}", resid, query),
+                ResProtocolRequest::Add(resid) => write!(f, "call.{}.add", resid),
+                ResProtocolRequest::New(resid) => write!(f, "call.{}.new", resid),
+                ResProtocolRequest::Set(resid) => write!(f, "call.{}.set", resid),
+                ResProtocolRequest::Delete(resid) => write!(f, "call.{}.delete", resid),
+                ResProtocolRequest::Remove(resid) => write!(f, "call.{}.remove", resid),
+                ResProtocolRequest::Access(resid) => write!(f, "access.{}", resid),
+                ResProtocolRequest::Call(resid, method) => write!(f, "call.{}.{}", resid, method),
+                ResProtocolRequest::Auth(resid, method) => write!(f, "auth.{}.{}", resid, method),
+                ResProtocolRequest::Unknown => write!(f, "??"),
             }
         }
     }
 
+    /// Splits `<rid>.<method>` at the last token, requiring both halves to be present
+    fn split_method(source: &str) -> Option<(&str, &str)> {
+        let idx = source.rfind('.')?;
+        let (resid, method) = (&source[..idx], &source[idx + 1..]);
+        if resid.is_empty() || method.is_empty() {
+            None
+        } else {
+            Some((resid, method))
+        }
+    }
+
     impl From<&str> for ResProtocolRequest {
         fn from(source: &str) -> Self {
-            if source.starts_with("get.") {
-                ResProtocolRequest::Get(source[4..].to_string())
-            } else if source.starts_with("call.") && source.ends_with(".new") {
-                ResProtocolRequest::New(source[5..=source.len() - 5].to_string())
-            } else if source.starts_with("call.") && source.ends_with(".add") {
-                ResProtocolRequest::Add(source[5..=source.len() - 5].to_string())            
-            } else if source.starts_with("call.") && source.ends_with(".set") {
-                ResProtocolRequest::Set(source[5..=source.len() - 5].to_string())
-            } else if source.starts_with("access.") {
-                ResProtocolRequest::Access(source[7..].to_string())
-            } else if source.ends_with("delete") {
-                ResProtocolRequest::Delete(source[5..=source.len() - 8].to_string())
-            } else if source.starts_with("call.") {
-                // a call that isn't new or set
-                let tokens: Vec<&str> = source.split('.').collect();
-                let rid = tokens[1..tokens.len() - 1].join(".");
-                let method = tokens[tokens.len() - 1];
-                ResProtocolRequest::Call(rid, method.to_string())
-            } else {
-                ResProtocolRequest::Unknown
+            let idx = match source.find('.') {
+                Some(idx) => idx,
+                None => return ResProtocolRequest::Unknown,
+            };
+            let (kind, rest) = (&source[..idx], &source[idx + 1..]);
+            if rest.is_empty() {
+                return ResProtocolRequest::Unknown;
+            }
+            match kind {
+                "get" => match rest.find('?') {
+                    Some(0) => ResProtocolRequest::Unknown,
+                    Some(q) if q == rest.len() - 1 => {
+                        ResProtocolRequest::Get(rest[..q].to_string())
+                    }
+                    Some(q) => {
+                        ResProtocolRequest::Query(rest[..q].to_string(), rest[q + 1..].to_string())
+                    }
+                    None => ResProtocolRequest::Get(rest.to_string()),
+                },
+                // access is granted per resource, regardless of any query
+                "access" => match rest.split('?').next() {
+                    Some(resid) if !resid.is_empty() => {
+                        ResProtocolRequest::Access(resid.to_string())
+                    }
+                    _ => ResProtocolRequest::Unknown,
+                },
+                "auth" => match split_method(rest) {
+                    Some((resid, method)) => {
+                        ResProtocolRequest::Auth(resid.to_string(), method.to_string())
+                    }
+                    None => ResProtocolRequest::Unknown,
+                },
+                "call" => match split_method(rest) {
+                    Some((resid, "new")) => ResProtocolRequest::New(resid.to_string()),
+                    Some((resid, "add")) => ResProtocolRequest::Add(resid.to_string()),
+                    Some((resid, "set")) => ResProtocolRequest::Set(resid.to_string()),
+                    Some((resid, "delete")) => ResProtocolRequest::Delete(resid.to_string()),
+                    Some((resid, "remove")) => ResProtocolRequest::Remove(resid.to_string()),
+                    Some((resid, method)) => {
+                        ResProtocolRequest::Call(resid.to_string(), method.to_string())
+                    }
+                    None => ResProtocolRequest::Unknown,
+                },
+                _ => ResProtocolRequest::Unknown,
             }
         }
     }
@@ -112,9 +680,9 @@ pub mod timer {
     /// The Waxosuit operation name for a timer tick
     pub const OP_TIMER_TICK: &str = "decs:timer!Tick";
 
-    impl Into<TimerTick> for &[u8] {
-        fn into(self) -> TimerTick {
-            TimerTick::decode(self).unwrap()
+    impl From<&[u8]> for TimerTick {
+        fn from(source: &[u8]) -> TimerTick {
+            TimerTick::decode(source).unwrap()
         }
     }
 
@@ -127,29 +695,97 @@ pub mod timer {
         pub elapsed_ms: u32,
         /// The name/ID of the shard for which this tick is bound
         pub shard: String,
+        /// The state of the shard when the tick was produced. Paused shards are still
+        /// ticked so their housekeeping carries on, but their systems don't run
+        #[serde(default)]
+        pub state: crate::shard::ShardState,
     }
 
     impl GameLoopTick {
         /// Converts a Waxosuit timer tick into a game loop tick
-        pub fn from_tick(source: &TimerTick, shard: &str) -> Self {
+        pub fn from_tick(source: &TimerTick, shard: &str, state: crate::shard::ShardState) -> Self {
             GameLoopTick {
                 seq_no: source.seq_no as _,
                 elapsed_ms: source.elapsed_ms as _,
                 shard: shard.to_string(),
+                state,
             }
         }
     }
 }
 
 pub mod users {
-//! Support for User data serialization
+    //! Support for User data serialization
 
-    /// Represents a game user
+    /// Name of the role that grants unrestricted access to every resource
+    pub const ROLE_ADMIN: &str = "admin";
+
+    /// Represents a game user. When stored, `pass` holds a password hash rather than
+    /// the password itself
     #[derive(Debug, Serialize, Deserialize, Default)]
     pub struct User {
         pub email: String,
         pub pass: String,
-        pub id: String
+        pub id: String,
+        /// Roles granted to the user, copied into the access token at login
+        #[serde(default)]
+        pub roles: Vec<String>,
+    }
+
+    impl User {
+        /// Produces the public view of the user, safe to emit in RES models and events
+        pub fn profile(&self) -> UserProfile {
+            UserProfile {
+                email: self.email.clone(),
+                id: self.id.clone(),
+                roles: self.roles.clone(),
+            }
+        }
+    }
+
+    /// The publicly visible fields of a user, without any credentials
+    #[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+    pub struct UserProfile {
+        pub email: String,
+        pub id: String,
+        #[serde(default)]
+        pub roles: Vec<String>,
+    }
+
+    /// The token attached to an authenticated RES connection. RESgate passes it along
+    /// in the `token` field of every access, get and call request made on that connection
+    #[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+    pub struct AccessToken {
+        /// ID of the user who owns the connection
+        pub user_id: String,
+        /// Roles granted to the user when the token was issued
+        #[serde(default)]
+        pub roles: Vec<String>,
+    }
+
+    impl AccessToken {
+        /// Extracts the token from the body of a RES request. Returns `None` for
+        /// unauthenticated connections or malformed bodies
+        pub fn from_request(body: &[u8]) -> Option<AccessToken> {
+            let v: serde_json::Value = serde_json::from_slice(body).ok()?;
+            serde_json::from_value(v["token"].clone()).ok()
+        }
+
+        /// Indicates whether the token grants the admin role
+        pub fn is_admin(&self) -> bool {
+            self.roles.iter().any(|r| r == ROLE_ADMIN)
+        }
+
+        /// Indicates whether the token grants the given role. Admins hold every role
+        pub fn has_role(&self, role: &str) -> bool {
+            self.is_admin() || self.roles.iter().any(|r| r == role)
+        }
+    }
+
+    /// Checks the token of a RES request for a role, for use in access and call handlers.
+    /// Unauthenticated requests hold no roles
+    pub fn request_has_role(body: &[u8], role: &str) -> bool {
+        AccessToken::from_request(body).is_some_and(|t| t.has_role(role))
     }
 }
 
@@ -166,6 +802,55 @@ pub mod shard {
         /// Current number of component values contained within the shard
         #[serde(default)]
         pub current: u32,
+        /// Where the shard is in its lifecycle, e.g. paused for maintenance
+        #[serde(default)]
+        pub state: ShardState,
+    }
+
+    /// The lifecycle state of a shard
+    #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum ShardState {
+        /// The shard's systems run and its components may be changed freely
+        #[default]
+        Running,
+        /// The shard's systems are frozen, but its components may still be changed and the
+        /// game loop keeps ticking it for housekeeping such as expiry sweeps
+        Paused,
+        /// The shard's systems run, but it takes no new components, so entities may
+        /// change, leave or be deleted while none spawn or move in
+        Draining,
+        /// The shard's systems run, but none of its components may be changed
+        ReadOnly,
+    }
+
+    impl ShardState {
+        /// Indicates whether systems should run on a shard in this state
+        pub fn runs_systems(self) -> bool {
+            self != ShardState::Paused
+        }
+
+        /// Indicates whether a shard in this state may have its components changed
+        pub fn accepts_writes(self) -> bool {
+            self != ShardState::ReadOnly
+        }
+
+        /// Indicates whether a shard in this state may have components added to it
+        pub fn accepts_new_components(self) -> bool {
+            matches!(self, ShardState::Running | ShardState::Paused)
+        }
+    }
+
+    impl std::fmt::Display for ShardState {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let state = match self {
+                ShardState::Running => "running",
+                ShardState::Paused => "paused",
+                ShardState::Draining => "draining",
+                ShardState::ReadOnly => "read-only",
+            };
+            write!(f, "{}", state)
+        }
     }
 
     impl Shard {
@@ -175,11 +860,92 @@ pub mod shard {
                 name: "the_void".to_string(),
                 capacity: 1_000,
                 current: 0,
+                state: ShardState::Running,
             }
         }
     }
 }
 
+pub mod components {
+    //! The key-value layout the component manager keeps components in. The shard manager
+    //! walks the same keys to purge and recount a shard, so both go through these helpers
+
+    use crate::gateway::Rid;
+
+    /// Type recorded for a model component or a collection item
+    pub const TYPE_MODEL: &str = "M";
+    /// Type recorded for a collection component
+    pub const TYPE_COLLECTION: &str = "C";
+
+    /// The key under which a component (model or collection) of an entity is kept.
+    /// decs:components:{shard}:{entity}:{component}
+    pub fn component_key(shard: &str, entity: &str, component: &str) -> String {
+        Rid::component(shard, entity, component).to_key()
+    }
+
+    /// The key under which a collection item is kept, given its resource ID
+    pub fn item_key(item_rid: &str) -> String {
+        item_rid.replace('.', ":")
+    }
+
+    /// The key for the type (`TYPE_MODEL` or `TYPE_COLLECTION`) of the value under `key`
+    pub fn type_key(key: &str) -> String {
+        format!("{}:type", key)
+    }
+
+    /// The key for the revision of the value under `key`
+    pub fn revision_key(key: &str) -> String {
+        format!("{}:rev", key)
+    }
+
+    /// The key for the counter handing out the item IDs of the collection under `key`
+    pub fn item_id_key(key: &str) -> String {
+        format!("{}:id", key)
+    }
+
+    /// The key for the lock held while the order of the collection under `key` changes
+    pub fn lock_key(key: &str) -> String {
+        format!("{}:lock", key)
+    }
+
+    /// The key for the ID of the user owning an entity.
+    /// decs:{shard}:{entity}:owner
+    pub fn owner_key(shard: &str, entity: &str) -> String {
+        format!("decs:{}:{}:owner", shard, entity)
+    }
+
+    /// The key for the list of component names an entity has.
+    /// decs:{shard}:{entity}:components
+    pub fn entity_components_key(shard: &str, entity: &str) -> String {
+        format!("decs:{}:{}:components", shard, entity)
+    }
+
+    /// The key for the set of entities which have a given component.
+    /// decs:{shard}:{component}:entities
+    pub fn component_entities_key(shard: &str, component: &str) -> String {
+        format!("decs:{}:{}:entities", shard, component)
+    }
+
+    /// The key for the set of all entities in a shard.
+    /// decs:{shard}:entities
+    pub fn shard_entities_key(shard: &str) -> String {
+        format!("decs:{}:entities", shard)
+    }
+
+    /// The key for the set of users owning at least one entity in a shard.
+    /// decs:{shard}:users
+    pub fn shard_users_key(shard: &str) -> String {
+        format!("decs:{}:users", shard)
+    }
+
+    /// The key for the set of resource IDs of components and collection items in a shard
+    /// that were written with a time to live.
+    /// decs:{shard}:expiring
+    pub fn shard_expiring_key(shard: &str) -> String {
+        format!("decs:{}:expiring", shard)
+    }
+}
+
 pub mod systemmgr {
     //! Support for types related to system management
 
@@ -208,83 +974,1104 @@ pub mod systemmgr {
     }
 }
 
+pub mod kv {
+    //! An abstraction over the Waxosuit key-value capability, so that store logic can be
+    //! exercised against an in-memory stand-in as well as the real (Redis-backed) provider
+
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+    /// The operations offered by the key-value capability provider. Semantics follow Redis
+    pub trait KeyValue {
+        fn get(&self, key: &str) -> Result<Option<String>>;
+        fn set(&self, key: &str, value: &str, expires: Option<u32>) -> Result<()>;
+        fn atomic_add(&self, key: &str, value: i32) -> Result<i32>;
+        /// Pushes an item onto the head of a list, returning the new length of the list
+        fn list_add(&self, key: &str, item: &str) -> Result<usize>;
+        /// Removes every occurrence of an item from a list, returning the number removed
+        fn list_del_item(&self, key: &str, item: &str) -> Result<usize>;
+        fn del_key(&self, key: &str) -> Result<()>;
+        fn list_range(&self, key: &str, start: isize, stop_inclusive: isize)
+            -> Result<Vec<String>>;
+        fn list_clear(&self, key: &str) -> Result<()>;
+        /// Adds a member to a set, returning the number of members actually added
+        fn set_add(&self, key: &str, value: &str) -> Result<usize>;
+        /// Removes a member from a set, returning the number of members actually removed
+        fn set_remove(&self, key: &str, value: &str) -> Result<usize>;
+        fn set_union<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>>;
+        fn set_intersect<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>>;
+        fn set_members(&self, key: &str) -> Result<Vec<String>>;
+        fn exists(&self, key: &str) -> Result<bool>;
+    }
+
+    #[cfg(feature = "guest")]
+    impl KeyValue for waxosuit_guest::kv::KeyValueStore {
+        fn get(&self, key: &str) -> Result<Option<String>> {
+            Ok(self.get(key)?)
+        }
+        fn set(&self, key: &str, value: &str, expires: Option<u32>) -> Result<()> {
+            Ok(self.set(key, value, expires)?)
+        }
+        fn atomic_add(&self, key: &str, value: i32) -> Result<i32> {
+            Ok(self.atomic_add(key, value)?)
+        }
+        fn list_add(&self, key: &str, item: &str) -> Result<usize> {
+            Ok(self.list_add(key, item)?)
+        }
+        fn list_del_item(&self, key: &str, item: &str) -> Result<usize> {
+            Ok(self.list_del_item(key, item)?)
+        }
+        fn del_key(&self, key: &str) -> Result<()> {
+            Ok(self.del_key(key)?)
+        }
+        fn list_range(
+            &self,
+            key: &str,
+            start: isize,
+            stop_inclusive: isize,
+        ) -> Result<Vec<String>> {
+            Ok(self.list_range(key, start, stop_inclusive)?)
+        }
+        fn list_clear(&self, key: &str) -> Result<()> {
+            Ok(self.list_clear(key)?)
+        }
+        fn set_add(&self, key: &str, value: &str) -> Result<usize> {
+            Ok(self.set_add(key, value)?)
+        }
+        fn set_remove(&self, key: &str, value: &str) -> Result<usize> {
+            Ok(self.set_remove(key, value)?)
+        }
+        fn set_union<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>> {
+            Ok(self.set_union(keys)?)
+        }
+        fn set_intersect<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>> {
+            Ok(self.set_intersect(keys)?)
+        }
+        fn set_members(&self, key: &str) -> Result<Vec<String>> {
+            Ok(self.set_members(key)?)
+        }
+        fn exists(&self, key: &str) -> Result<bool> {
+            Ok(self.exists(key)?)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Entry {
+        Value(String),
+        List(Vec<String>),
+        Set(Vec<String>),
+    }
+
+    const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+    /// An in-memory key-value store for tests. Expiry is accepted but ignored, and sets
+    /// report their members in insertion order
+    #[derive(Debug, Default)]
+    pub struct MemoryStore {
+        data: RefCell<HashMap<String, Entry>>,
+    }
+
+    impl MemoryStore {
+        pub fn new() -> MemoryStore {
+            MemoryStore::default()
+        }
+
+        /// All keys currently held by the store, sorted
+        pub fn keys(&self) -> Vec<String> {
+            let mut keys: Vec<String> = self.data.borrow().keys().cloned().collect();
+            keys.sort();
+            keys
+        }
+
+        fn with_list<R>(&self, key: &str, f: impl FnOnce(&mut Vec<String>) -> R) -> Result<R> {
+            let mut data = self.data.borrow_mut();
+            let entry = data
+                .entry(key.to_string())
+                .or_insert_with(|| Entry::List(vec![]));
+            let res = match entry {
+                Entry::List(ref mut l) => f(l),
+                _ => return Err(WRONG_TYPE.into()),
+            };
+            // Redis deletes keys holding empty aggregates
+            if let Entry::List(ref l) = data[key] {
+                if l.is_empty() {
+                    data.remove(key);
+                }
+            }
+            Ok(res)
+        }
+
+        fn with_set<R>(&self, key: &str, f: impl FnOnce(&mut Vec<String>) -> R) -> Result<R> {
+            let mut data = self.data.borrow_mut();
+            let entry = data
+                .entry(key.to_string())
+                .or_insert_with(|| Entry::Set(vec![]));
+            let res = match entry {
+                Entry::Set(ref mut s) => f(s),
+                _ => return Err(WRONG_TYPE.into()),
+            };
+            if let Entry::Set(ref s) = data[key] {
+                if s.is_empty() {
+                    data.remove(key);
+                }
+            }
+            Ok(res)
+        }
+    }
+
+    impl KeyValue for MemoryStore {
+        fn get(&self, key: &str) -> Result<Option<String>> {
+            match self.data.borrow().get(key) {
+                Some(Entry::Value(v)) => Ok(Some(v.clone())),
+                Some(_) => Err(WRONG_TYPE.into()),
+                None => Ok(None),
+            }
+        }
+
+        fn set(&self, key: &str, value: &str, _expires: Option<u32>) -> Result<()> {
+            self.data
+                .borrow_mut()
+                .insert(key.to_string(), Entry::Value(value.to_string()));
+            Ok(())
+        }
+
+        fn atomic_add(&self, key: &str, value: i32) -> Result<i32> {
+            let current: i32 = match self.get(key)? {
+                Some(v) => v.parse()?,
+                None => 0,
+            };
+            let new_value = current + value;
+            self.set(key, &new_value.to_string(), None)?;
+            Ok(new_value)
+        }
+
+        fn list_add(&self, key: &str, item: &str) -> Result<usize> {
+            self.with_list(key, |l| {
+                l.insert(0, item.to_string());
+                l.len()
+            })
+        }
+
+        fn list_del_item(&self, key: &str, item: &str) -> Result<usize> {
+            self.with_list(key, |l| {
+                let before = l.len();
+                l.retain(|i| i != item);
+                before - l.len()
+            })
+        }
+
+        fn del_key(&self, key: &str) -> Result<()> {
+            self.data.borrow_mut().remove(key);
+            Ok(())
+        }
+
+        fn list_range(
+            &self,
+            key: &str,
+            start: isize,
+            stop_inclusive: isize,
+        ) -> Result<Vec<String>> {
+            self.with_list(key, |l| {
+                let len = l.len() as isize;
+                let norm = |i: isize| if i < 0 { len + i } else { i };
+                let start = norm(start).max(0);
+                let stop = norm(stop_inclusive).min(len - 1);
+                if start > stop {
+                    vec![]
+                } else {
+                    l[start as usize..=stop as usize].to_vec()
+                }
+            })
+        }
+
+        fn list_clear(&self, key: &str) -> Result<()> {
+            self.del_key(key)
+        }
+
+        fn set_add(&self, key: &str, value: &str) -> Result<usize> {
+            self.with_set(key, |s| {
+                if s.iter().any(|m| m == value) {
+                    0
+                } else {
+                    s.push(value.to_string());
+                    1
+                }
+            })
+        }
+
+        fn set_remove(&self, key: &str, value: &str) -> Result<usize> {
+            self.with_set(key, |s| {
+                let before = s.len();
+                s.retain(|m| m != value);
+                before - s.len()
+            })
+        }
+
+        fn set_union<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>> {
+            let mut union: Vec<String> = vec![];
+            for key in keys {
+                for member in self.set_members(key.as_ref())? {
+                    if !union.contains(&member) {
+                        union.push(member);
+                    }
+                }
+            }
+            Ok(union)
+        }
+
+        fn set_intersect<T: AsRef<str>>(&self, keys: &[T]) -> Result<Vec<String>> {
+            let mut sets = vec![];
+            for key in keys {
+                sets.push(self.set_members(key.as_ref())?);
+            }
+            match sets.split_first() {
+                Some((first, rest)) => Ok(first
+                    .iter()
+                    .filter(|m| rest.iter().all(|s| s.contains(m)))
+                    .cloned()
+                    .collect()),
+                None => Ok(vec![]),
+            }
+        }
+
+        fn set_members(&self, key: &str) -> Result<Vec<String>> {
+            self.with_set(key, |s| s.clone())
+        }
+
+        fn exists(&self, key: &str) -> Result<bool> {
+            Ok(self.data.borrow().contains_key(key))
+        }
+    }
+}
+
+pub mod schema {
+    //! Validation of component values against the subset of JSON Schema used to describe them.
+    //!
+    //! The supported keywords are `type`, `enum`, `const`, `properties`, `required`,
+    //! `additionalProperties`, `items`, `minItems`, `maxItems`, `minimum`, `maximum`,
+    //! `exclusiveMinimum`, `exclusiveMaximum`, `minLength` and `maxLength`. Any other keyword
+    //! is ignored, as JSON Schema prescribes for unknown keywords
+
+    use serde_json::Value;
+
+    /// One way in which a value fails to conform to a schema
+    #[derive(Debug, Clone, PartialEq, Serialize)]
+    pub struct SchemaError {
+        /// JSON pointer to the offending value, e.g. `/velocity/x`
+        pub path: String,
+        pub message: String,
+    }
+
+    const TYPES: &[&str] = &[
+        "null", "boolean", "object", "array", "number", "integer", "string",
+    ];
+
+    /// Checks that a schema is well formed: an object (or boolean) whose supported keywords
+    /// all have values of the right shape
+    pub fn check_schema(schema: &Value) -> Result<(), String> {
+        check_schema_at(schema, "")
+    }
+
+    /// Validates a value against a schema, collecting every violation found
+    pub fn validate(schema: &Value, value: &Value) -> Result<(), Vec<SchemaError>> {
+        let mut errors = vec![];
+        validate_at(schema, value, "", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_schema_at(schema: &Value, path: &str) -> Result<(), String> {
+        let obj = match schema {
+            Value::Bool(_) => return Ok(()),
+            Value::Object(obj) => obj,
+            _ => return Err(format!("schema at '{}' must be an object", path)),
+        };
+        let bad = |keyword: &str, expected: &str| {
+            Err(format!("'{}' at '{}' must be {}", keyword, path, expected))
+        };
+        for (keyword, v) in obj {
+            match keyword.as_str() {
+                "type" => {
+                    let names: Vec<&Value> = match v {
+                        Value::Array(names) => names.iter().collect(),
+                        name => vec![name],
+                    };
+                    if !names
+                        .iter()
+                        .all(|n| n.as_str().is_some_and(|n| TYPES.contains(&n)))
+                    {
+                        return bad(keyword, "a type name or an array of type names");
+                    }
+                }
+                "enum" if !v.is_array() => return bad(keyword, "an array"),
+                "required" if !v.as_array().is_some_and(|r| r.iter().all(Value::is_string)) => {
+                    return bad(keyword, "an array of property names")
+                }
+                "properties" => match v.as_object() {
+                    Some(props) => {
+                        for (name, prop) in props {
+                            check_schema_at(prop, &format!("{}/properties/{}", path, name))?;
+                        }
+                    }
+                    None => return bad(keyword, "an object"),
+                },
+                "additionalProperties" | "items" => {
+                    check_schema_at(v, &format!("{}/{}", path, keyword))?
+                }
+                "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum"
+                    if !v.is_number() =>
+                {
+                    return bad(keyword, "a number")
+                }
+                "minItems" | "maxItems" | "minLength" | "maxLength" if !v.is_u64() => {
+                    return bad(keyword, "a non-negative integer")
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn type_matches(name: &str, value: &Value) -> bool {
+        match name {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "number" => value.is_number(),
+            "integer" => {
+                value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|f| f.fract() == 0.0)
+            }
+            "string" => value.is_string(),
+            _ => false,
+        }
+    }
+
+    /// Escapes a property name for use in a JSON pointer
+    fn pointer(path: &str, segment: &str) -> String {
+        format!("{}/{}", path, segment.replace('~', "~0").replace('/', "~1"))
+    }
+
+    fn validate_at(schema: &Value, value: &Value, path: &str, errors: &mut Vec<SchemaError>) {
+        let mut fail = |message: String| {
+            errors.push(SchemaError {
+                path: path.to_string(),
+                message,
+            })
+        };
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return fail("is not allowed".to_string()),
+            Value::Object(obj) => obj,
+            _ => return,
+        };
+
+        match schema.get("type") {
+            Some(Value::String(name)) if !type_matches(name, value) => {
+                fail(format!("must be of type {}", name))
+            }
+            Some(Value::Array(names))
+                if !names
+                    .iter()
+                    .any(|n| n.as_str().is_some_and(|n| type_matches(n, value))) =>
+            {
+                fail(format!(
+                    "must be one of the types {}",
+                    Value::Array(names.clone())
+                ))
+            }
+            _ => {}
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                fail(format!("must be one of {}", Value::Array(allowed.clone())));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                fail(format!("must be {}", expected));
+            }
+        }
+
+        if let Some(n) = value.as_f64() {
+            let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+            if let Some(min) = limit("minimum").filter(|min| n < *min) {
+                fail(format!("must be at least {}", min));
+            }
+            if let Some(max) = limit("maximum").filter(|max| n > *max) {
+                fail(format!("must be at most {}", max));
+            }
+            if let Some(min) = limit("exclusiveMinimum").filter(|min| n <= *min) {
+                fail(format!("must be greater than {}", min));
+            }
+            if let Some(max) = limit("exclusiveMaximum").filter(|max| n >= *max) {
+                fail(format!("must be less than {}", max));
+            }
+        }
+
+        if let Some(s) = value.as_str() {
+            let len = s.chars().count() as u64;
+            let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+            if let Some(min) = limit("minLength").filter(|min| len < *min) {
+                fail(format!("must be at least {} characters long", min));
+            }
+            if let Some(max) = limit("maxLength").filter(|max| len > *max) {
+                fail(format!("must be at most {} characters long", max));
+            }
+        }
+
+        if let Some(items) = value.as_array() {
+            let len = items.len() as u64;
+            let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+            if let Some(min) = limit("minItems").filter(|min| len < *min) {
+                fail(format!("must have at least {} items", min));
+            }
+            if let Some(max) = limit("maxItems").filter(|max| len > *max) {
+                fail(format!("must have at most {} items", max));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &pointer(path, &i.to_string()), errors);
+                }
+            }
+        }
+
+        if let Some(obj) = value.as_object() {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !obj.contains_key(name) {
+                        errors.push(SchemaError {
+                            path: pointer(path, name),
+                            message: "is required".to_string(),
+                        });
+                    }
+                }
+            }
+            let props = schema.get("properties").and_then(Value::as_object);
+            for (name, prop_value) in obj {
+                let prop_path = pointer(path, name);
+                match props.and_then(|p| p.get(name)) {
+                    Some(prop_schema) => validate_at(prop_schema, prop_value, &prop_path, errors),
+                    None => {
+                        if let Some(extra) = schema.get("additionalProperties") {
+                            validate_at(extra, prop_value, &prop_path, errors);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub mod ids {
+    //! Generation of server-assigned resource ids
+
+    use super::kv::{KeyValue, Result};
+    use blake2::digest::consts::U16;
+    use blake2::{Blake2b, Digest};
+
+    /// Generates a new unique id, formatted as a (version 8) UUID. WebAssembly guests have no
+    /// source of randomness, so uniqueness comes from the counter stored under `seq_key`; the
+    /// counter value is hashed so that consecutive ids don't look sequential
+    pub fn next_uuid(kv: &impl KeyValue, seq_key: &str) -> Result<String> {
+        let seq = kv.atomic_add(seq_key, 1)?;
+        Ok(uuid_from_seq(seq_key, seq))
+    }
+
+    fn uuid_from_seq(seq_key: &str, seq: i32) -> String {
+        let mut hasher = Blake2b::<U16>::new();
+        hasher.update(seq_key.as_bytes());
+        hasher.update(seq.to_le_bytes());
+        let mut b = hasher.finalize();
+        b[6] = (b[6] & 0x0f) | 0x80; // version 8
+        b[8] = (b[8] & 0x3f) | 0x80; // RFC 4122 variant
+
+        let hex: String = b.iter().map(|x| format!("{:02x}", x)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::gateway::ResProtocolRequest;
+    use super::gateway::{self, ErrorCode, ResEvent, ResProtocolRequest, Rid, RidError};
+    use super::ids;
+    use super::kv::{KeyValue, MemoryStore};
+    use super::schema;
+    use super::shard::{Shard, ShardState};
+    use super::users::{self, AccessToken};
+    use proptest::prelude::*;
 
     #[test]
-    fn test_resprotocol_roundtrip() {
-        let mut subject = "call.decs.components.the_void.player1.radar_contacts.new";
-        let mut req = ResProtocolRequest::from(subject);
+    fn test_memory_store() {
+        let kv = MemoryStore::new();
+        assert_eq!(kv.get("a").unwrap(), None);
+        kv.set("a", "1", None).unwrap();
+        assert_eq!(kv.atomic_add("a", 2).unwrap(), 3);
+        assert_eq!(kv.get("a").unwrap(), Some("3".to_string()));
+        assert!(kv.list_add("a", "x").is_err());
+
+        assert_eq!(kv.list_add("l", "x").unwrap(), 1);
+        assert_eq!(kv.list_add("l", "y").unwrap(), 2);
+        assert_eq!(kv.list_add("l", "x").unwrap(), 3);
+        assert_eq!(kv.list_range("l", 0, -1).unwrap(), vec!["x", "y", "x"]);
+        assert_eq!(kv.list_range("l", 1, 1).unwrap(), vec!["y"]);
+        assert_eq!(kv.list_range("l", -2, -1).unwrap(), vec!["y", "x"]);
+        assert_eq!(kv.list_del_item("l", "x").unwrap(), 2);
+        assert_eq!(kv.list_range("l", 0, -1).unwrap(), vec!["y"]);
+        assert_eq!(kv.list_del_item("l", "y").unwrap(), 1);
+        assert!(!kv.exists("l").unwrap());
+
+        assert_eq!(kv.set_add("s1", "a").unwrap(), 1);
+        assert_eq!(kv.set_add("s1", "a").unwrap(), 0);
+        kv.set_add("s1", "b").unwrap();
+        kv.set_add("s2", "b").unwrap();
+        kv.set_add("s2", "c").unwrap();
+        assert_eq!(kv.set_intersect(&["s1", "s2"]).unwrap(), vec!["b"]);
+        assert_eq!(kv.set_union(&["s1", "s2"]).unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(kv.set_remove("s1", "z").unwrap(), 0);
+        assert_eq!(kv.set_remove("s1", "a").unwrap(), 1);
+        assert_eq!(kv.set_members("s1").unwrap(), vec!["b"]);
+        assert_eq!(kv.keys(), vec!["a", "s1", "s2"]);
+    }
+
+    #[test]
+    fn test_shard_state() {
+        let shard: Shard = serde_json::from_str(r#"{"name": "zone1", "capacity": 10}"#).unwrap();
+        assert_eq!(shard.state, ShardState::Running);
+        let shard: Shard =
+            serde_json::from_value(json!({"name": "zone1", "capacity": 10, "state": "read-only"}))
+                .unwrap();
+        assert_eq!(shard.state, ShardState::ReadOnly);
+        assert_eq!(json!(shard)["state"], "read-only");
+        assert!(serde_json::from_value::<Shard>(
+            json!({"name": "zone1", "capacity": 10, "state": "frozen"})
+        )
+        .is_err());
+
+        for state in [
+            ShardState::Running,
+            ShardState::Paused,
+            ShardState::Draining,
+            ShardState::ReadOnly,
+        ] {
+            assert_eq!(json!(state), json!(state.to_string()));
+        }
+        assert!(!ShardState::Paused.runs_systems());
+        let gtick: crate::timer::GameLoopTick =
+            serde_json::from_str(r#"{"seq_no": 1, "elapsed_ms": 100, "shard": "s"}"#).unwrap();
+        assert_eq!(gtick.state, ShardState::Running);
+        assert!(ShardState::Paused.accepts_new_components());
+        assert!(ShardState::Draining.accepts_writes());
+        assert!(!ShardState::Draining.accepts_new_components());
+        assert!(!ShardState::ReadOnly.accepts_writes());
+    }
+
+    #[test]
+    fn test_token_from_request() {
+        let body = br#"{"token": {"user_id": "bob", "roles": ["admin"]}, "cid": "abc"}"#;
+        let token = AccessToken::from_request(body).unwrap();
+        assert_eq!(token.user_id, "bob");
+        assert!(token.is_admin());
+
+        let body = br#"{"token": {"user_id": "alice"}, "cid": "abc"}"#;
+        assert!(!AccessToken::from_request(body).unwrap().is_admin());
+
         assert_eq!(
-            req,
-            ResProtocolRequest::New("decs.components.the_void.player1.radar_contacts".into())
+            AccessToken::from_request(br#"{"token": null, "cid": "abc"}"#),
+            None
         );
+        assert_eq!(AccessToken::from_request(b"not json"), None);
+    }
+
+    #[test]
+    fn test_request_has_role() {
+        let body = br#"{"token": {"user_id": "bob", "roles": ["designer"]}}"#;
+        assert!(users::request_has_role(body, "designer"));
+        assert!(!users::request_has_role(body, users::ROLE_ADMIN));
+
+        let body = br#"{"token": {"user_id": "alice", "roles": ["admin"]}}"#;
+        assert!(users::request_has_role(body, "designer"));
+
+        assert!(!users::request_has_role(br#"{"token": null}"#, "designer"));
+    }
+
+    #[test]
+    fn test_next_uuid() {
+        let kv = MemoryStore::new();
+        let a = ids::next_uuid(&kv, "seq").unwrap();
+        let b = ids::next_uuid(&kv, "seq").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 36);
+        assert_eq!(&a[14..15], "8");
+        // Ids must be usable as RID segments
+        let rid = Rid::User(a.clone()).to_string();
+        assert_eq!(rid.parse::<Rid>().unwrap(), Rid::User(a));
+    }
+
+    #[test]
+    fn test_schema_validation() {
+        let position = json!({
+            "type": "object",
+            "properties": {
+                "x": {"type": "number"},
+                "y": {"type": "number"},
+                "sector": {"type": "string", "maxLength": 8},
+                "hp": {"type": "integer", "minimum": 0, "maximum": 100},
+                "tags": {"type": "array", "items": {"enum": ["friend", "foe"]}}
+            },
+            "required": ["x", "y"],
+            "additionalProperties": false
+        });
+        assert_eq!(schema::check_schema(&position), Ok(()));
         assert_eq!(
-            req.to_string(),
-            "call.decs.components.the_void.player1.radar_contacts.new"
+            schema::validate(&position, &json!({"x": 1.5, "y": -2, "hp": 40.0})),
+            Ok(())
         );
 
-        subject = "call.decs.components.the_void.player1.radar_contacts.delete";
-        req = ResProtocolRequest::from(subject);
+        let errors = schema::validate(
+            &position,
+            &json!({"x": "1", "hp": 101, "sector": "alpha_centauri", "tags": ["foe", "ally"], "z": 0}),
+        )
+        .unwrap_err();
+        let mut paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/hp", "/sector", "/tags/1", "/x", "/y", "/z"]);
+        assert!(schema::validate(&position, &json!([1, 2])).is_err());
+    }
+
+    #[test]
+    fn test_check_schema() {
+        assert!(schema::check_schema(&json!(true)).is_ok());
+        assert!(schema::check_schema(&json!({"type": ["number", "null"]})).is_ok());
+        assert!(schema::check_schema(&json!("object")).is_err());
+        assert!(schema::check_schema(&json!({"type": "float"})).is_err());
+        assert!(schema::check_schema(&json!({"required": "x"})).is_err());
+        assert!(schema::check_schema(&json!({"maxLength": -1})).is_err());
+        assert!(schema::check_schema(&json!({"properties": {"x": {"minimum": "0"}}})).is_err());
+    }
+
+    #[test]
+    fn test_result_builders() {
         assert_eq!(
-            req,
-            ResProtocolRequest::Delete("decs.components.the_void.player1.radar_contacts".into())
+            gateway::collection_result(vec![Rid::Shard("a".into()), Rid::Shard("b".into())]),
+            json!({"result": {"collection": [{"rid": "decs.shard.a"}, {"rid": "decs.shard.b"}]}})
         );
         assert_eq!(
-            req.to_string(),
-            "call.decs.components.the_void.player1.radar_contacts.delete"
+            gateway::query_collection_result(vec!["decs.shard.a"], "limit=1"),
+            json!({"result": {"collection": [{"rid": "decs.shard.a"}], "query": "limit=1"}})
         );
+        assert_eq!(
+            gateway::access_result(true, None),
+            json!({"result": {"get": true, "call": null}})
+        );
+        assert_eq!(
+            gateway::resource_result("decs.user.bob"),
+            json!({"result": {"rid": "decs.user.bob"}})
+        );
+    }
+
+    #[test]
+    fn test_error_builders() {
+        assert_eq!(
+            gateway::error_access_denied("nope"),
+            json!({"error": {"code": "system.accessDenied", "message": "nope"}})
+        );
+        assert_eq!(
+            gateway::error_timeout("slow")["error"]["code"],
+            "system.timeout"
+        );
+        assert_eq!(
+            gateway::error_internal("boom")["error"]["code"],
+            "system.internalError"
+        );
+        assert_eq!(
+            gateway::error_method_not_found("what")["error"]["code"],
+            "system.methodNotFound"
+        );
+        assert_eq!(
+            gateway::error_conflict("stale")["error"]["code"],
+            "decs.conflict"
+        );
+        assert_eq!(
+            gateway::error_response_with_data(
+                ErrorCode::Custom("decs.zoneClosed".into()),
+                "closed",
+                json!({"reopens": 10})
+            ),
+            json!({"error": {"code": "decs.zoneClosed", "message": "closed", "data": {"reopens": 10}}})
+        );
+        assert_eq!(
+            gateway::error_shard_full("full", 10, 9),
+            json!({"error": {"code": "decs.shardFull", "message": "full", "data": {"capacity": 10, "current": 9}}})
+        );
+        assert_eq!(
+            gateway::error_shard_closed("frozen", ShardState::ReadOnly),
+            json!({"error": {"code": "decs.shardClosed", "message": "frozen", "data": {"state": "read-only"}}})
+        );
+    }
+
+    #[test]
+    fn test_merge_model() {
+        let mut model = json!({"x": 1, "y": 2, "heading": "north"});
+        let patch = json!({"x": 1, "y": 5, "heading": null, "speed": 3, "missing": null});
+        let changed =
+            gateway::merge_model(model.as_object_mut().unwrap(), patch.as_object().unwrap());
+        assert_eq!(model, json!({"x": 1, "y": 5, "speed": 3}));
+        assert_eq!(
+            serde_json::Value::Object(changed),
+            json!({"y": 5, "heading": {"action": "delete"}, "speed": 3})
+        );
+
+        let changed = gateway::merge_model(
+            model.as_object_mut().unwrap(),
+            json!({"x": 1}).as_object().unwrap(),
+        );
+        assert!(changed.is_empty());
+    }
 
-        subject = "get.decs.components.the_void.player1.radar_contacts.1";
-        req = ResProtocolRequest::from(subject);
+    #[test]
+    fn test_event_builders() {
+        let add = ResEvent::add("decs.shards", "decs.shard.a", 2);
+        assert_eq!(add.subject, "event.decs.shards.add");
         assert_eq!(
-            req,
-            ResProtocolRequest::Get("decs.components.the_void.player1.radar_contacts.1".into())
+            add.payload,
+            Some(json!({"value": {"rid": "decs.shard.a"}, "idx": 2}))
         );
+
+        let remove = ResEvent::remove("decs.shards", 1);
+        assert_eq!(remove.subject, "event.decs.shards.remove");
+        assert_eq!(remove.body(), br#"{"idx":1}"#.to_vec());
+
+        let change = ResEvent::change("decs.shard.a", json!({"current": 3}));
+        assert_eq!(change.subject, "event.decs.shard.a.change");
+        assert_eq!(change.payload, Some(json!({"values": {"current": 3}})));
+
+        let delete = ResEvent::delete("decs.shard.a");
+        assert_eq!(delete.subject, "event.decs.shard.a.delete");
+        assert!(delete.body().is_empty());
+
+        let query = ResEvent::query("decs.entities.a", "query.decs.entities.a");
+        assert_eq!(query.subject, "event.decs.entities.a.query");
         assert_eq!(
-            req.to_string(),
-            "get.decs.components.the_void.player1.radar_contacts.1"
+            query.payload,
+            Some(json!({"subject": "query.decs.entities.a"}))
         );
 
-        subject = "call.decs.components.the_void.player1.position.set";
-        req = ResProtocolRequest::from(subject);
+        let reaccess = ResEvent::reaccess("decs.shard.a");
+        assert_eq!(reaccess.subject, "event.decs.shard.a.reaccess");
+        assert!(reaccess.body().is_empty());
+
+        let unsub = ResEvent::unsubscribe("decs.shard.a", ErrorCode::NotFound, "gone");
+        assert_eq!(unsub.subject, "event.decs.shard.a.unsubscribe");
         assert_eq!(
-            req,
-            ResProtocolRequest::Set("decs.components.the_void.player1.position".into())
+            unsub.payload,
+            Some(json!({"reason": {"code": "system.notFound", "message": "gone"}}))
         );
+
+        let token = ResEvent::connection_token("abc", json!({"user_id": "bob"}));
+        assert_eq!(token.subject, "conn.abc.token");
+        assert_eq!(token.payload, Some(json!({"token": {"user_id": "bob"}})));
+
+        let reset = ResEvent::system_reset(&["decs.shard.>"], &[]);
+        assert_eq!(reset.subject, "system.reset");
         assert_eq!(
-            req.to_string(),
-            "call.decs.components.the_void.player1.position.set"
+            reset.payload,
+            Some(json!({"resources": ["decs.shard.>"], "access": []}))
         );
+    }
 
-        subject = "access.decs.components.the_void.player1.radar_contacts.1";
-        req = ResProtocolRequest::from(subject);
+    #[test]
+    fn test_rid_roundtrip() {
+        let rids = [
+            "decs.components.the_void",
+            "decs.components.the_void.player1",
+            "decs.components.the_void.player1.position",
+            "decs.components.the_void.player1.radar_contacts.1",
+            "decs.entities.the_void",
+            "decs.schemas.position",
+            "decs.prefabs.freighter",
+            "decs.shard.the_void",
+            "decs.shards",
+            "decs.system.physics",
+            "decs.systems",
+            "decs.user.bob",
+            "decs.users",
+        ];
+        for source in rids.iter() {
+            let rid: Rid = source.parse().unwrap();
+            assert_eq!(rid.to_string(), *source);
+        }
+    }
+
+    #[test]
+    fn test_rid_component_parts() {
+        let rid: Rid = "decs.components.the_void.player1.radar_contacts.1"
+            .parse()
+            .unwrap();
         assert_eq!(
-            req,
-            ResProtocolRequest::Access("decs.components.the_void.player1.radar_contacts.1".into())
+            rid,
+            Rid::Component {
+                shard: "the_void".into(),
+                entity: "player1".into(),
+                component: Some("radar_contacts".into()),
+                item: Some("1".into()),
+            }
         );
+        assert_eq!(rid.shard(), Some("the_void"));
+        assert_eq!(rid.entity(), Some("player1"));
         assert_eq!(
-            req.to_string(),
-            "access.decs.components.the_void.player1.radar_contacts.1"
+            rid.entity_rid().unwrap().to_string(),
+            "decs.components.the_void.player1"
         );
+        assert_eq!(
+            rid.to_key(),
+            "decs:components:the_void:player1:radar_contacts:1"
+        );
+        assert_eq!(
+            Rid::component("the_void", "player1", "position").to_string(),
+            "decs.components.the_void.player1.position"
+        );
+    }
+
+    #[test]
+    fn test_rid_rejects_malformed() {
+        assert!(matches!(
+            "decs.components".parse::<Rid>(),
+            Err(RidError::SegmentCount(_))
+        ));
+        assert!(matches!(
+            "decs.components.a.b.c.d.e".parse::<Rid>(),
+            Err(RidError::SegmentCount(_))
+        ));
+        assert!(matches!(
+            "decs.entities.the_void.player1".parse::<Rid>(),
+            Err(RidError::SegmentCount(_))
+        ));
+        assert!(matches!(
+            "decs.shard".parse::<Rid>(),
+            Err(RidError::SegmentCount(_))
+        ));
+        assert!(matches!(
+            "decs.shards.extra".parse::<Rid>(),
+            Err(RidError::SegmentCount(_))
+        ));
+        assert!(matches!(
+            "decs.widgets.x".parse::<Rid>(),
+            Err(RidError::UnknownResource(_))
+        ));
+        assert!(matches!(
+            "notdecs.shard.x".parse::<Rid>(),
+            Err(RidError::BadNamespace(_))
+        ));
+        assert!(matches!("".parse::<Rid>(), Err(RidError::BadNamespace(_))));
+        assert!(matches!(
+            "decs.components.the_void..position".parse::<Rid>(),
+            Err(RidError::InvalidSegment(_))
+        ));
+        assert!(matches!(
+            "decs.user.*".parse::<Rid>(),
+            Err(RidError::InvalidSegment(_))
+        ));
+        assert!(matches!(
+            "decs.components.a.b:c".parse::<Rid>(),
+            Err(RidError::InvalidSegment(_))
+        ));
+    }
 
-        subject = "call.decs.shard.the_void.set";
-        req = ResProtocolRequest::from(subject);
-        assert_eq!(req, ResProtocolRequest::Set("decs.shard.the_void".into()));
-        assert_eq!(req.to_string(), "call.decs.shard.the_void.set");
+    #[test]
+    fn test_resprotocol_examples() {
+        let cases = vec![
+            (
+                "call.decs.components.the_void.player1.radar_contacts.new",
+                ResProtocolRequest::New("decs.components.the_void.player1.radar_contacts".into()),
+            ),
+            (
+                "call.decs.components.the_void.player1.radar_contacts.delete",
+                ResProtocolRequest::Delete(
+                    "decs.components.the_void.player1.radar_contacts".into(),
+                ),
+            ),
+            (
+                "call.decs.components.the_void.player1.radar_contacts.remove",
+                ResProtocolRequest::Remove(
+                    "decs.components.the_void.player1.radar_contacts".into(),
+                ),
+            ),
+            (
+                "get.decs.components.the_void.player1.radar_contacts.1",
+                ResProtocolRequest::Get("decs.components.the_void.player1.radar_contacts.1".into()),
+            ),
+            (
+                "call.decs.components.the_void.player1.position.set",
+                ResProtocolRequest::Set("decs.components.the_void.player1.position".into()),
+            ),
+            (
+                "access.decs.components.the_void.player1.radar_contacts.1",
+                ResProtocolRequest::Access(
+                    "decs.components.the_void.player1.radar_contacts.1".into(),
+                ),
+            ),
+            (
+                "call.decs.shard.the_void.set",
+                ResProtocolRequest::Set("decs.shard.the_void".into()),
+            ),
+            (
+                "call.decs.shard.the_void.incr",
+                ResProtocolRequest::Call("decs.shard.the_void".into(), "incr".into()),
+            ),
+            (
+                "call.decs.users.add",
+                ResProtocolRequest::Add("decs.users".into()),
+            ),
+            (
+                "get.decs.systems?limit=10",
+                ResProtocolRequest::Query("decs.systems".into(), "limit=10".into()),
+            ),
+            (
+                "auth.decs.users.login",
+                ResProtocolRequest::Auth("decs.users".into(), "login".into()),
+            ),
+        ];
+        for (subject, expected) in cases {
+            let req = ResProtocolRequest::from(subject);
+            assert_eq!(req, expected);
+            assert_eq!(req.to_string(), subject);
+        }
+    }
 
-        subject = "call.decs.shard.the_void.incr";
-        req = ResProtocolRequest::from(subject);
+    #[test]
+    fn test_resprotocol_strict_prefixes() {
+        // a get whose resource happens to end in a method name is still a get
+        assert_eq!(
+            ResProtocolRequest::from("get.decs.components.the_void.player1.delete"),
+            ResProtocolRequest::Get("decs.components.the_void.player1.delete".into())
+        );
         assert_eq!(
-            req,
-            ResProtocolRequest::Call("decs.shard.the_void".into(), "incr".into())
+            ResProtocolRequest::from("access.decs.shard.set"),
+            ResProtocolRequest::Access("decs.shard.set".into())
         );
-        assert_eq!(req.to_string(), "call.decs.shard.the_void.incr");
+        assert_eq!(
+            ResProtocolRequest::from("decs.shard.the_void.delete"),
+            ResProtocolRequest::Unknown
+        );
+        assert_eq!(
+            ResProtocolRequest::from("getdecs.shards"),
+            ResProtocolRequest::Unknown
+        );
+        // access is per resource, so any query is dropped
+        assert_eq!(
+            ResProtocolRequest::from("access.decs.systems?limit=10"),
+            ResProtocolRequest::Access("decs.systems".into())
+        );
+        // an empty query is a plain get
+        assert_eq!(
+            ResProtocolRequest::from("get.decs.systems?"),
+            ResProtocolRequest::Get("decs.systems".into())
+        );
+    }
+
+    #[test]
+    fn test_resprotocol_incomplete_subjects() {
+        for subject in [
+            "",
+            "get",
+            "get.",
+            "get.?a=1",
+            "call",
+            "call.",
+            "call.set",
+            "call.decs.",
+            "auth.login",
+            "auth.",
+            "access.",
+            "event.decs.shards.add",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                ResProtocolRequest::from(*subject),
+                ResProtocolRequest::Unknown,
+                "subject: {}",
+                subject
+            );
+        }
+    }
+
+    fn rid_strategy() -> impl Strategy<Value = String> {
+        prop::collection::vec("[a-z0-9_]{1,10}", 1..6).prop_map(|segs| segs.join("."))
+    }
+
+    fn method_strategy() -> impl Strategy<Value = String> {
+        "[a-zA-Z]{1,12}".prop_filter("reserved call method", |m| {
+            !["new", "add", "set", "delete", "remove"].contains(&m.as_str())
+        })
+    }
+
+    fn request_strategy() -> impl Strategy<Value = ResProtocolRequest> {
+        prop_oneof![
+            rid_strategy().prop_map(ResProtocolRequest::Get),
+            (
+                rid_strategy(),
+                "[a-z]{1,8}=[a-z0-9]{1,8}(&[a-z]{1,8}=[a-z0-9,]{1,8}){0,3}"
+            )
+                .prop_map(|(r, q)| ResProtocolRequest::Query(r, q)),
+            rid_strategy().prop_map(ResProtocolRequest::Add),
+            rid_strategy().prop_map(ResProtocolRequest::New),
+            rid_strategy().prop_map(ResProtocolRequest::Set),
+            rid_strategy().prop_map(ResProtocolRequest::Delete),
+            rid_strategy().prop_map(ResProtocolRequest::Remove),
+            rid_strategy().prop_map(ResProtocolRequest::Access),
+            (rid_strategy(), method_strategy()).prop_map(|(r, m)| ResProtocolRequest::Call(r, m)),
+            (rid_strategy(), "[a-zA-Z]{1,12}").prop_map(|(r, m)| ResProtocolRequest::Auth(r, m)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_resprotocol_roundtrip(req in request_strategy()) {
+            let subject = req.to_string();
+            prop_assert_eq!(ResProtocolRequest::from(subject.as_str()), req);
+        }
+
+        #[test]
+        fn test_resprotocol_rid_has_no_query(req in request_strategy()) {
+            let rid = req.rid().unwrap();
+            prop_assert!(!rid.contains('?'));
+            prop_assert!(!rid.is_empty());
+        }
 
-        subject = "call.decs.users.add";
-        req = ResProtocolRequest::from(subject);
-        assert_eq!(req, ResProtocolRequest::Add("decs.users".into()));
-        assert_eq!(req.to_string(), "call.decs.users.add");
+        #[test]
+        fn test_rid_segment_rejects_colon(
+            segs in prop::collection::vec("[a-z0-9_]{0,6}:[a-z0-9_]{0,6}|[a-z0-9_]{1,6}", 3),
+        ) {
+            let rid = format!("decs.components.{}", segs.join("."));
+            match rid.parse::<Rid>() {
+                Ok(parsed) => {
+                    prop_assert!(segs.iter().all(|s| !s.contains(':')));
+                    prop_assert_eq!(parsed.to_key(), rid.replace('.', ":"));
+                }
+                Err(e) => {
+                    prop_assert!(segs.iter().any(|s| s.contains(':')));
+                    prop_assert!(matches!(e, RidError::InvalidSegment(_)));
+                }
+            }
+        }
+
+        #[test]
+        fn test_resprotocol_never_panics(subject in "\\PC{0,40}") {
+            let _ = ResProtocolRequest::from(subject.as_str());
+        }
     }
 }
@@ -29,13 +29,16 @@ pub fn handle_call(ctx: &CapabilitiesContext, operation: &str, msg: &[u8]) -> Ca
 
 /// Every time the game loop ticks, publish a "loop tick" on decs.(shard).gameloop
 /// This allows all system managers to receive distributed loop ticks, and can allow
-/// a single system manager to subscribe to ticks for a single shard
+/// a single system manager to subscribe to ticks for a single shard. Every shard is
+/// ticked whatever its state, so housekeeping carries on while a shard is paused; the
+/// tick carries the state so systems can sit those ticks out
 fn tick(ctx: &CapabilitiesContext, tick: impl Into<decs::timer::TimerTick>) -> CallResult {
     let tick = tick.into();
     let shards = store::get_shards(ctx)?;
 
     for shard in shards.iter() {
-        let gtick = decs::timer::GameLoopTick::from_tick(&tick, &shard);
+        let state = store::get_shard_state(ctx, shard)?;
+        let gtick = decs::timer::GameLoopTick::from_tick(&tick, shard, state);
         ctx.msg().publish(
             &format!("decs.{}.gameloop", shard),
             None,
@@ -1,3 +1,4 @@
+use decscloud_common::shard::{Shard, ShardState};
 use guest::prelude::*;
 
 const SHARDS_KEY: &str = "decs:shards";
@@ -9,3 +10,11 @@ const SHARDS_KEY: &str = "decs:shards";
 pub(crate) fn get_shards(ctx: &CapabilitiesContext) -> Result<Vec<String>> {
     ctx.kv().set_members(SHARDS_KEY)
 }
+
+/// Retrieves the state of a shard. A shard without details yet is taken to be running
+pub(crate) fn get_shard_state(ctx: &CapabilitiesContext, shard: &str) -> Result<ShardState> {
+    match ctx.kv().get(&format!("decs:shard:{}", shard))? {
+        Some(v) => Ok(serde_json::from_str::<Shard>(&v)?.state),
+        None => Ok(ShardState::default()),
+    }
+}
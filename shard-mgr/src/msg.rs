@@ -5,13 +5,19 @@
 //!    get.decs.shard.* ([GW GET]/api/decs/shard/{shard-name})
 //!    access.decs.shard.*
 //!    access.decs.shards
-//!    call.decs.shards.*.set (sets/creates a shard)
+//!    call.decs.shards.*.set (sets/creates a shard, or changes its state, admin only)
+//!    call.decs.shard.*.incr (adjusts a shard's component count, component manager only)
+//!    call.decs.shard.*.reserve (makes room for new components, component manager only)
+//!    call.decs.shard.*.delete (retires a shard)
+//!    call.decs.shard.*.recount (corrects a shard's component count from the store)
+//!    decs.*.gameloop (periodically recounts each shard's components)
 //!
 
 use crate::store;
 use decscloud_common as codec;
-use decscloud_common::gateway::{ResProtocolRequest, ResourceIdentifier};
+use decscloud_common::gateway::{ResEvent, ResProtocolRequest, Rid};
 use decscloud_common::shard::Shard;
+use decscloud_common::users::AccessToken;
 use guest::prelude::*;
 
 /// Examine the subject of the message and invoke the appopriate function
@@ -22,14 +28,37 @@ pub fn handle_message(
     let msg = msg.into().message;
     if let Some(msg) = msg {
         match ResProtocolRequest::from(msg.subject.as_str()) {
-            ResProtocolRequest::Get(_) if msg.subject.ends_with("shards") => {
-                handle_get_collection(ctx, &msg)
-            }
-            ResProtocolRequest::Get(_) => handle_get_single(ctx, &msg),
+            ResProtocolRequest::Get(ref rid) => match rid.parse::<Rid>() {
+                Ok(Rid::Shards) => handle_get_collection(ctx, &msg),
+                Ok(Rid::Shard(ref name)) => handle_get_single(ctx, &msg, name),
+                other => reply_invalid_rid(ctx, &msg, other),
+            },
             ResProtocolRequest::Set(_) => handle_set(ctx, &msg),
             ResProtocolRequest::Access(_) => handle_access(ctx, &msg),
-            ResProtocolRequest::Call(_, ref operation) if operation == "incr" => {
-                handle_incr(ctx, &msg)
+            ResProtocolRequest::Call(ref rid, ref operation) if operation == "incr" => {
+                match rid.parse::<Rid>() {
+                    Ok(Rid::Shard(ref name)) => handle_incr(ctx, &msg, name),
+                    other => reply_invalid_rid(ctx, &msg, other),
+                }
+            }
+            ResProtocolRequest::Delete(ref rid) => match rid.parse::<Rid>() {
+                Ok(Rid::Shard(ref name)) => handle_delete(ctx, &msg, name),
+                other => reply_invalid_rid(ctx, &msg, other),
+            },
+            ResProtocolRequest::Call(ref rid, ref operation) if operation == "recount" => {
+                match rid.parse::<Rid>() {
+                    Ok(Rid::Shard(ref name)) => handle_recount(ctx, &msg, name),
+                    other => reply_invalid_rid(ctx, &msg, other),
+                }
+            }
+            ResProtocolRequest::Unknown if msg.subject.ends_with(GAMELOOP_SUFFIX) => {
+                handle_gameloop(ctx, &msg)
+            }
+            ResProtocolRequest::Call(ref rid, ref operation) if operation == "reserve" => {
+                match rid.parse::<Rid>() {
+                    Ok(Rid::Shard(ref name)) => handle_reserve(ctx, &msg, name),
+                    other => reply_invalid_rid(ctx, &msg, other),
+                }
             }
             _ => Err("unknown service request format".into()),
         }
@@ -46,39 +75,227 @@ pub fn handle_message(
 ///   "cid" : ... connection id ...
 /// }
 /// ```
+/// A set that leaves out the shard's `state` keeps the state the shard already has, so that
+/// e.g. changing its capacity doesn't resume a paused shard. Only admins may set shards
 fn handle_set(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let shard = extract_shard_from_set(&msg.body)?;
+    if AccessToken::from_request(&msg.body).is_some_and(|t| !t.is_admin()) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may set shards"),
+        );
+    }
+    let (mut shard, has_state) = extract_shard_from_set(&msg.body)?;
     ctx.log(&format!(
         "Handling set request: {}, reply-to: {}",
         msg.subject, msg.reply_to
     ));
+    if !has_state {
+        match store::get_shard_details(ctx.kv(), &shard.name) {
+            Ok(existing) => shard.state = existing.state,
+            Err(ref e) if e.to_string() == store::NOT_FOUND => {}
+            Err(e) => return Err(e),
+        }
+    }
     set_shard(ctx, &shard)
 }
 
 /// The component manager will make this call when it sets or deletes a component
 /// from within a shard. The maintenance of this count is the shard's responsibility. When
 /// the shard changes as a result of this new component count, it will publish a model
-/// change event so RESgate listeners can see it
-fn handle_incr(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+/// change event so RESgate listeners can see it. Clients may not make this call
+fn handle_incr(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+) -> CallResult {
+    if from_client(&msg.body) {
+        return reply_internal_only(ctx, msg);
+    }
     let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
     let amt: i32 = v["params"]["amount"].as_i64().unwrap_or(0) as i32;
     if amt != 0 {
-        let tokens: Vec<&str> = msg.subject.split('.').collect(); // call.decs.shard.().incr
-        let shard = tokens[3];
-        let new_shard = store::incr_shard(ctx, shard, amt)?;
+        let new_shard = store::incr_shard(ctx.kv(), shard, amt)?;
         publish_model_change(ctx, &new_shard)
     } else {
         Ok(vec![])
     }
 }
 
+/// The component manager makes this request before it adds components to a shard. The
+/// reply is a success once room for `amount` more components has been taken from the
+/// shard's capacity, and a `decs.shardFull` error if they would not fit. Checking and
+/// counting happen as one, so concurrent writers can't overfill a shard between them.
+/// Clients may not make this call
+fn handle_reserve(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+) -> CallResult {
+    if from_client(&msg.body) {
+        return reply_internal_only(ctx, msg);
+    }
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let amount = match v["params"]["amount"].as_u64() {
+        Some(amount) if amount > 0 && amount <= i32::MAX as u64 => amount as u32,
+        _ => {
+            return reply(
+                ctx,
+                msg,
+                &codec::gateway::error_invalid_params("amount must be a positive integer"),
+            )
+        }
+    };
+    match store::reserve_shard(ctx.kv(), shard, amount) {
+        Ok(store::Reservation::Granted(new_shard)) => {
+            publish_model_change(ctx, &new_shard)?;
+            reply(
+                ctx,
+                msg,
+                &codec::gateway::call_result(json!({ "current": new_shard.current })),
+            )
+        }
+        Ok(store::Reservation::Closed(shard)) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_shard_closed(
+                &format!(
+                    "Shard {} is {} and takes no new components",
+                    shard.name, shard.state
+                ),
+                shard.state,
+            ),
+        ),
+        Ok(store::Reservation::Full(shard)) => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_shard_full(
+                &format!(
+                    "Shard {} has no room for {} more components",
+                    shard.name, amount
+                ),
+                shard.capacity,
+                shard.current,
+            ),
+        ),
+        Err(ref e) if e.to_string() == store::NOT_FOUND => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_not_found(&format!("No such shard: {}", shard)),
+        ),
+        Err(e) => Err(e),
+    }
+}
+
+/// Deletes a shard, so that it leaves the collection of shards and the game loop stops
+/// ticking it. Only an admin (or a server-side caller, without a token) may delete a shard.
+/// A shard with anything still in it is refused unless the params have `force` set, in
+/// which case all of its entities and components are purged with it
+fn handle_delete(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+) -> CallResult {
+    if AccessToken::from_request(&msg.body).is_some_and(|t| !t.is_admin()) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may delete shards"),
+        );
+    }
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let force = v["params"]["force"].as_bool().unwrap_or(false);
+    match store::delete_shard(ctx.kv(), shard, force) {
+        Ok(idx) => {
+            ctx.log(&format!("Deleted shard {}", shard));
+            publish_event(ctx, &ResEvent::remove(&Rid::Shards.to_string(), idx))?;
+            publish_event(
+                ctx,
+                &ResEvent::delete(&Rid::Shard(shard.to_string()).to_string()),
+            )?;
+            reply(ctx, msg, &codec::gateway::success_response())
+        }
+        Err(ref e) if e.to_string() == store::NOT_EMPTY => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_conflict(&format!(
+                "Shard {} still holds components, delete with force to purge them",
+                shard
+            )),
+        ),
+        Err(ref e) if e.to_string() == store::NOT_FOUND => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_not_found(&format!("No such shard: {}", shard)),
+        ),
+        Err(e) => Err(e),
+    }
+}
+
+/// Recounts a shard's components from the store, correcting the count kept by `incr` and
+/// `reserve` if it has drifted. Replies with the shard's count
+fn handle_recount(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    shard: &str,
+) -> CallResult {
+    match recount(ctx, shard) {
+        Ok(shard) => reply(
+            ctx,
+            msg,
+            &codec::gateway::call_result(json!({ "current": shard.current })),
+        ),
+        Err(ref e) if e.to_string() == store::NOT_FOUND => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_not_found(&format!("No such shard: {}", shard)),
+        ),
+        Err(e) => Err(e),
+    }
+}
+
+const GAMELOOP_SUFFIX: &str = ".gameloop";
+
+/// Recount a shard's components every this many of its game loop ticks, once a minute
+/// at the default 10 ticks per second
+const RECOUNT_EVERY_TICKS: u64 = 600;
+
+/// Upon receipt of a game loop tick, periodically reconciles the shard's component count
+/// with what the store holds
+fn handle_gameloop(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    let gtick: codec::timer::GameLoopTick = serde_json::from_slice(&msg.body)?;
+    if gtick.seq_no.is_multiple_of(RECOUNT_EVERY_TICKS) {
+        if let Err(e) = recount(ctx, &gtick.shard) {
+            // a shard deleted since the tick has nothing left to count
+            if e.to_string() != store::NOT_FOUND {
+                return Err(e);
+            }
+        }
+    }
+    Ok(vec![])
+}
+
+/// Recounts a shard's components, publishing a change event with the corrected count if
+/// it was wrong
+fn recount(ctx: &CapabilitiesContext, shard: &str) -> codec::kv::Result<Shard> {
+    let (shard, previous) = store::recount_shard(ctx.kv(), shard)?;
+    if shard.current != previous {
+        ctx.log(&format!(
+            "Corrected component count of shard {} from {} to {}",
+            shard.name, previous, shard.current
+        ));
+        publish_model_change(ctx, &shard)?;
+    }
+    Ok(shard)
+}
+
 fn set_shard(ctx: &CapabilitiesContext, shard: &Shard) -> CallResult {
-    match store::put_shard(ctx, &shard) {
+    match store::put_shard(ctx.kv(), shard) {
         Ok((pos, existed)) => {
             if !existed {
-                publish_collection_add(ctx, &shard, pos)
+                publish_collection_add(ctx, shard, pos)
             } else {
-                publish_model_change(ctx, &shard)
+                publish_model_change(ctx, shard)
             }
         }
         Err(e) => Err(e),
@@ -86,41 +303,36 @@ fn set_shard(ctx: &CapabilitiesContext, shard: &Shard) -> CallResult {
 }
 
 fn publish_collection_add(ctx: &CapabilitiesContext, shard: &Shard, pos: usize) -> CallResult {
-    let subject = "event.decs.shards.add";
-    let item = format!("decs.shard.{}", shard.name);
-    let rid = ResourceIdentifier { rid: item };
-    let out = json!({
-        "value": rid,
-        "idx": pos
-    });
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    Ok(vec![])
+    let item = Rid::Shard(shard.name.clone()).to_string();
+    publish_event(ctx, &ResEvent::add(&Rid::Shards.to_string(), &item, pos))
 }
 
 fn publish_model_change(ctx: &CapabilitiesContext, shard: &Shard) -> CallResult {
-    let item = format!("decs.shard.{}", shard.name);
-    let subject = format!("event.{}.change", item);
+    let item = Rid::Shard(shard.name.clone()).to_string();
+    publish_event(ctx, &ResEvent::change(&item, json!(shard)))
+}
 
-    let out = json!({ "values": shard });
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
+fn publish_event(ctx: &CapabilitiesContext, event: &ResEvent) -> CallResult {
+    ctx.msg().publish(&event.subject, None, &event.body())?;
     Ok(vec![])
 }
 
-fn extract_shard_from_set(body: &[u8]) -> Result<Shard> {
+/// Reads the shard from the params of a set, along with whether they include its state
+fn extract_shard_from_set(body: &[u8]) -> Result<(Shard, bool)> {
     let v: serde_json::Value = serde_json::from_slice(body)?;
     let shard = &v["params"];
-    Ok(serde_json::from_value(shard.clone())?)
+    let has_state = !shard["state"].is_null();
+    Ok((serde_json::from_value(shard.clone())?, has_state))
 }
 
+/// Anyone may read shards, but only admins may call methods on them. `incr` and `reserve`
+/// are left out, as only the component manager makes those calls
 fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let result = json!({
-        "result" : {
-            "get" : true,
-            "call" : "*"
-        }
-    });
+    let call = match AccessToken::from_request(&msg.body) {
+        Some(ref t) if t.is_admin() => Some("set,delete,recount"),
+        _ => None,
+    };
+    let result = codec::gateway::access_result(true, call);
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
@@ -129,7 +341,7 @@ fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> C
 fn handle_get_collection(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
     // If we don't have any shards, create "the void" by default
     let shardlist = {
-        let l = store::get_shards(ctx)?;
+        let l = store::get_shards(ctx.kv())?;
         if l.is_empty() {
             let thevoid = codec::shard::Shard::the_void();
             set_shard(ctx, &thevoid)?;
@@ -139,59 +351,90 @@ fn handle_get_collection(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessa
         }
     };
 
-    let rids: Vec<_> = shardlist
-        .iter()
-        .map(|s| codec::gateway::ResourceIdentifier {
-            rid: format!("decs.shard.{}", s),
-        })
-        .collect();
-    let result = json!({
-        "result": {
-            "collection": rids
-        }
-    });
+    let result = codec::gateway::collection_result(shardlist.into_iter().map(Rid::Shard));
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
 }
 
-fn handle_get_single(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let tokens: Vec<&str> = msg.subject.split('.').collect();
-    if tokens.len() != 4 {
-        // get.decs.shard.xxx
-        Err("incorrectly formatted single-shard get request".into())
-    } else {
-        match store::get_shard_details(ctx, tokens[3]) {
-            Ok(shard) => {
-                let result = json!({
-                    "result": {
-                        "model": {
-                            "name": shard.name,
-                            "current": shard.current,
-                            "capacity": shard.capacity
-                        }
-                    }
-                });
-                ctx.msg()
-                    .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
-            }
-            Err(ref e) if e.to_string() == store::NOT_FOUND => {
-                ctx.msg().publish(
-                    &msg.reply_to,
-                    None,
-                    &serde_json::to_vec(&codec::gateway::error_not_found("No such component"))?,
-                )?;
-            }
-            Err(e) => {
-                ctx.msg().publish(
-                    &msg.reply_to,
-                    None,
-                    &serde_json::to_vec(&codec::gateway::error_invalid_params(&format!("{}", e)))?,
-                )?;
-                ctx.log(&format!("Failed to retrieve component: {}", e));
-                return Err(e);
-            }
+fn handle_get_single(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    name: &str,
+) -> CallResult {
+    match store::get_shard_details(ctx.kv(), name) {
+        Ok(shard) => {
+            let result = codec::gateway::model_result(json!({
+                "name": shard.name,
+                "current": shard.current,
+                "capacity": shard.capacity,
+                "state": shard.state
+            }));
+            ctx.msg()
+                .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
         }
-        Ok(vec![])
+        Err(ref e) if e.to_string() == store::NOT_FOUND => {
+            ctx.msg().publish(
+                &msg.reply_to,
+                None,
+                &serde_json::to_vec(&codec::gateway::error_not_found("No such component"))?,
+            )?;
+        }
+        Err(e) => {
+            ctx.msg().publish(
+                &msg.reply_to,
+                None,
+                &serde_json::to_vec(&codec::gateway::error_invalid_params(&format!("{}", e)))?,
+            )?;
+            ctx.log(&format!("Failed to retrieve component: {}", e));
+            return Err(e);
+        }
+    }
+    Ok(vec![])
+}
+
+/// Indicates whether a request came from a client through RESgate, which passes along the
+/// connection's token. The component manager's own requests carry none
+fn from_client(body: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body).is_ok_and(|v| !v["token"].is_null())
+}
+
+fn reply_internal_only(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    reply(
+        ctx,
+        msg,
+        &codec::gateway::error_access_denied("Only the component manager may make this call"),
+    )
+}
+
+fn reply(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    result: &serde_json::Value,
+) -> CallResult {
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(result)?)?;
+    }
+    Ok(vec![])
+}
+
+/// Answers a request whose resource ID is malformed or not a shard resource
+fn reply_invalid_rid(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: std::result::Result<Rid, codec::gateway::RidError>,
+) -> CallResult {
+    let err = match rid {
+        Ok(other) => format!("not a shard resource: {}", other),
+        Err(e) => e.to_string(),
+    };
+    if !msg.reply_to.is_empty() {
+        ctx.msg().publish(
+            &msg.reply_to,
+            None,
+            &serde_json::to_vec(&codec::gateway::error_invalid_params(&err))?,
+        )?;
     }
+    Ok(vec![])
 }
@@ -1,30 +1,45 @@
 use decscloud_common as codec;
+use decscloud_common::components::{
+    component_entities_key, component_key, entity_components_key, item_id_key, item_key, lock_key,
+    owner_key, revision_key, shard_entities_key, shard_expiring_key, shard_users_key, type_key,
+    TYPE_COLLECTION,
+};
+use decscloud_common::kv::KeyValue;
 use decscloud_common::shard::Shard;
-use guest::prelude::*;
 
 const SHARDS_KEY: &str = "decs:shards";
 pub(crate) const NOT_FOUND: &str = "Not found";
+pub(crate) const NOT_EMPTY: &str = "Shard still holds components";
 
-pub(crate) fn get_shards(ctx: &CapabilitiesContext) -> Result<Vec<String>> {
-    ctx.kv().set_members(SHARDS_KEY)
+pub(crate) fn get_shards(kv: &impl KeyValue) -> codec::kv::Result<Vec<String>> {
+    kv.set_members(SHARDS_KEY)
+}
+
+fn shard_key(shard: &str) -> String {
+    format!("decs:shard:{}", shard)
 }
 
 fn count_key(shard: &str) -> String {
     format!("decs:shard:{}:count", shard)
 }
 
+/// A shard's component count as reported to clients. The stored counter can drift below
+/// zero, e.g. through decrements racing a recount, so it is never reported below zero
+fn reported_count(count: i32) -> u32 {
+    count.max(0) as u32
+}
+
 /// Creates or sets a shard. Returns a boolean indicating if the shard previously existed
 pub(crate) fn put_shard(
-    ctx: &CapabilitiesContext,
+    kv: &impl KeyValue,
     shard: &codec::shard::Shard,
-) -> std::result::Result<(usize, bool), Box<dyn std::error::Error>> {
-    let new_count = ctx.kv().set_add(SHARDS_KEY, &shard.name)?;
+) -> codec::kv::Result<(usize, bool)> {
+    let new_count = kv.set_add(SHARDS_KEY, &shard.name)?;
     let existed = new_count == 0;
-    let shard_key = format!("decs:shard:{}", shard.name);
     let shard_json = serde_json::to_string(&shard)?;
-    ctx.kv().set(&shard_key, &shard_json, None)?;
+    kv.set(&shard_key(&shard.name), &shard_json, None)?;
 
-    let shards = ctx.kv().set_members(SHARDS_KEY)?;
+    let shards = kv.set_members(SHARDS_KEY)?;
     match shards.iter().position(|s| *s == shard.name) {
         Some(p) => Ok((p, existed)),
         None => Err("item not in set".into()),
@@ -32,20 +47,20 @@ pub(crate) fn put_shard(
 }
 
 pub(crate) fn get_shard_details(
-    ctx: &CapabilitiesContext,
+    kv: &impl KeyValue,
     shard: &str,
-) -> std::result::Result<codec::shard::Shard, Box<dyn std::error::Error>> {
-    let shard_key = format!("decs:shard:{}", shard);
-
-    if let Some(v) = ctx.kv().get(&shard_key)? {
+) -> codec::kv::Result<codec::shard::Shard> {
+    if let Some(v) = kv.get(&shard_key(shard))? {
         match serde_json::from_str::<codec::shard::Shard>(&v) {
             Ok(r) => {
-                let current: u32 = ctx
-                    .kv()
+                let current: i32 = kv
                     .get(&count_key(shard))?
                     .unwrap_or_else(|| "0".to_string())
                     .parse()?;
-                Ok(Shard { current, ..r })
+                Ok(Shard {
+                    current: reported_count(current),
+                    ..r
+                })
             }
             Err(e) => Err(e.into()),
         }
@@ -54,12 +69,348 @@ pub(crate) fn get_shard_details(
     }
 }
 
-pub(crate) fn incr_shard(ctx: &CapabilitiesContext, shard: &str, amount: i32) -> Result<Shard> {
-    let key = count_key(shard);
-    let skey = format!("decs:shard:{}", shard);
-
-    let res = ctx.kv().atomic_add(&key, amount)?;
-    let mut s: Shard = serde_json::from_str(&ctx.kv().get(&skey)?.unwrap())?;
-    s.current = res as u32;
+pub(crate) fn incr_shard(kv: &impl KeyValue, shard: &str, amount: i32) -> codec::kv::Result<Shard> {
+    let res = kv.atomic_add(&count_key(shard), amount)?;
+    let mut s: Shard = match kv.get(&shard_key(shard))? {
+        Some(v) => serde_json::from_str(&v)?,
+        None => return Err(NOT_FOUND.into()),
+    };
+    s.current = reported_count(res);
     Ok(s)
 }
+
+/// The outcome of asking for room for more components in a shard
+#[derive(Debug)]
+pub(crate) enum Reservation {
+    /// The shard's count now includes the reserved components
+    Granted(Shard),
+    /// The components would not fit, and the shard's count is unchanged
+    Full(Shard),
+    /// The shard's state doesn't let it take new components, and its count is unchanged
+    Closed(Shard),
+}
+
+/// Reserves room for `amount` more components in a shard. The count is incremented first
+/// and only then compared with the capacity, handing the increment back if the shard
+/// overflowed, so concurrent reservations can never take a shard past its capacity. A
+/// shard that is draining or read-only grants nothing
+pub(crate) fn reserve_shard(
+    kv: &impl KeyValue,
+    shard: &str,
+    amount: u32,
+) -> codec::kv::Result<Reservation> {
+    let mut s: Shard = match kv.get(&shard_key(shard))? {
+        Some(v) => serde_json::from_str(&v)?,
+        None => return Err(NOT_FOUND.into()),
+    };
+    if !s.state.accepts_new_components() {
+        return Ok(Reservation::Closed(get_shard_details(kv, shard)?));
+    }
+    let amount = amount as i32;
+    let current = kv.atomic_add(&count_key(shard), amount)?;
+    if i64::from(current) > i64::from(s.capacity) {
+        s.current = reported_count(kv.atomic_add(&count_key(shard), -amount)?);
+        Ok(Reservation::Full(s))
+    } else {
+        s.current = reported_count(current);
+        Ok(Reservation::Granted(s))
+    }
+}
+
+/// Deletes a shard. A shard that still has entities or components is only deleted with
+/// `force`, which purges everything the component manager holds for it as well. Returns the
+/// index the shard occupied in the collection of shards
+pub(crate) fn delete_shard(
+    kv: &impl KeyValue,
+    shard: &str,
+    force: bool,
+) -> codec::kv::Result<usize> {
+    let idx = match get_shards(kv)?.iter().position(|s| s == shard) {
+        Some(idx) => idx,
+        None => return Err(NOT_FOUND.into()),
+    };
+    let entities = kv.set_members(&shard_entities_key(shard))?;
+    let count: i32 = kv
+        .get(&count_key(shard))?
+        .unwrap_or_else(|| "0".to_string())
+        .parse()?;
+    if !force && (count > 0 || !entities.is_empty()) {
+        return Err(NOT_EMPTY.into());
+    }
+    purge_components(kv, shard, &entities)?;
+
+    kv.set_remove(SHARDS_KEY, shard)?;
+    kv.del_key(&shard_key(shard))?;
+    kv.del_key(&count_key(shard))?;
+    Ok(idx)
+}
+
+/// Deletes the component manager's keys for a shard: its components and collection items
+/// and the indexes over them, laid out as `decscloud_common::components` describes. The
+/// key-value capability can't list keys by pattern, so they are found by walking the
+/// shard's entities and their components
+fn purge_components(kv: &impl KeyValue, shard: &str, entities: &[String]) -> codec::kv::Result<()> {
+    for entity in entities {
+        let components_key = entity_components_key(shard, entity);
+        for name in kv.list_range(&components_key, 0, -1)? {
+            let key = component_key(shard, entity, &name);
+            if kv.get(&type_key(&key))?.as_deref() == Some(TYPE_COLLECTION) {
+                for item in kv.list_range(&key, 0, -1)? {
+                    purge_value(kv, &item_key(&item))?;
+                }
+                kv.del_key(&item_id_key(&key))?;
+                kv.del_key(&lock_key(&key))?;
+            }
+            purge_value(kv, &key)?;
+            kv.del_key(&component_entities_key(shard, &name))?;
+        }
+        kv.del_key(&components_key)?;
+        kv.del_key(&owner_key(shard, entity))?;
+    }
+    kv.del_key(&shard_entities_key(shard))?;
+    kv.del_key(&shard_users_key(shard))?;
+    kv.del_key(&shard_expiring_key(shard))?;
+    Ok(())
+}
+
+/// Sets a shard's component count to the number of component values (models and collection
+/// items) the component manager actually holds for it, walking the layout described by
+/// `decscloud_common::components`. Values count from their first write until they are
+/// deleted or swept up after lapsing, so they are found by their types. Adjustments made
+/// while the shard is being counted may be lost, to be caught by the next recount. Returns
+/// the shard, and the count it had before
+pub(crate) fn recount_shard(kv: &impl KeyValue, shard: &str) -> codec::kv::Result<(Shard, u32)> {
+    let mut s = get_shard_details(kv, shard)?;
+    let previous = s.current;
+    let mut count = 0;
+    for entity in kv.set_members(&shard_entities_key(shard))? {
+        for name in kv.list_range(&entity_components_key(shard, &entity), 0, -1)? {
+            let key = component_key(shard, &entity, &name);
+            match kv.get(&type_key(&key))?.as_deref() {
+                Some(TYPE_COLLECTION) => {
+                    for item in kv.list_range(&key, 0, -1)? {
+                        if kv.exists(&type_key(&item_key(&item)))? {
+                            count += 1;
+                        }
+                    }
+                }
+                Some(_) => count += 1,
+                None => {}
+            }
+        }
+    }
+    kv.set(&count_key(shard), &count.to_string(), None)?;
+    s.current = count;
+    Ok((s, previous))
+}
+
+/// Deletes a component or collection item along with its type and revision
+fn purge_value(kv: &impl KeyValue, key: &str) -> codec::kv::Result<()> {
+    kv.del_key(key)?;
+    kv.del_key(&type_key(key))?;
+    kv.del_key(&revision_key(key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        delete_shard, get_shard_details, get_shards, incr_shard, put_shard, recount_shard,
+        reserve_shard, Reservation, NOT_EMPTY, NOT_FOUND,
+    };
+    use decscloud_common::components::{
+        component_entities_key, component_key, entity_components_key, item_id_key, item_key,
+        owner_key, revision_key, shard_entities_key, shard_expiring_key, shard_users_key, type_key,
+        TYPE_COLLECTION, TYPE_MODEL,
+    };
+    use decscloud_common::kv::{KeyValue, MemoryStore};
+    use decscloud_common::shard::{Shard, ShardState};
+
+    fn shard(name: &str, capacity: u32) -> Shard {
+        Shard {
+            name: name.to_string(),
+            capacity,
+            current: 0,
+            state: ShardState::Running,
+        }
+    }
+
+    #[test]
+    fn test_reserve_up_to_capacity() {
+        let kv = MemoryStore::new();
+        put_shard(&kv, &shard("zone1", 5)).unwrap();
+
+        match reserve_shard(&kv, "zone1", 3).unwrap() {
+            Reservation::Granted(s) => assert_eq!(s.current, 3),
+            other => panic!("unexpected {:?}", other),
+        }
+        match reserve_shard(&kv, "zone1", 3).unwrap() {
+            Reservation::Full(s) => assert_eq!((s.current, s.capacity), (3, 5)),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(get_shard_details(&kv, "zone1").unwrap().current, 3);
+        match reserve_shard(&kv, "zone1", 2).unwrap() {
+            Reservation::Granted(s) => assert_eq!(s.current, 5),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // releasing components makes room again
+        incr_shard(&kv, "zone1", -1).unwrap();
+        assert!(matches!(
+            reserve_shard(&kv, "zone1", 1).unwrap(),
+            Reservation::Granted(_)
+        ));
+        assert!(matches!(
+            reserve_shard(&kv, "zone1", 1).unwrap(),
+            Reservation::Full(_)
+        ));
+    }
+
+    #[test]
+    fn test_negative_count() {
+        let kv = MemoryStore::new();
+        put_shard(&kv, &shard("zone1", 5)).unwrap();
+        assert_eq!(incr_shard(&kv, "zone1", -3).unwrap().current, 0);
+        assert_eq!(get_shard_details(&kv, "zone1").unwrap().current, 0);
+
+        // the drift is made good by what is reserved, not reported as a huge count
+        match reserve_shard(&kv, "zone1", 5).unwrap() {
+            Reservation::Granted(s) => assert_eq!(s.current, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        match reserve_shard(&kv, "zone1", 4).unwrap() {
+            Reservation::Full(s) => assert_eq!(s.current, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reserve_by_state() {
+        let kv = MemoryStore::new();
+        for state in [
+            ShardState::Running,
+            ShardState::Paused,
+            ShardState::Draining,
+            ShardState::ReadOnly,
+        ] {
+            put_shard(
+                &kv,
+                &Shard {
+                    state,
+                    ..shard("zone1", 5)
+                },
+            )
+            .unwrap();
+            let granted = matches!(
+                reserve_shard(&kv, "zone1", 1).unwrap(),
+                Reservation::Granted(_)
+            );
+            assert_eq!(granted, state.accepts_new_components(), "{}", state);
+        }
+        assert_eq!(get_shard_details(&kv, "zone1").unwrap().current, 2);
+    }
+
+    #[test]
+    fn test_reserve_unknown_shard() {
+        let kv = MemoryStore::new();
+        let err = reserve_shard(&kv, "nowhere", 1).unwrap_err();
+        assert_eq!(err.to_string(), NOT_FOUND);
+        let err = incr_shard(&kv, "nowhere", 1).unwrap_err();
+        assert_eq!(err.to_string(), NOT_FOUND);
+    }
+
+    /// Lays out an entity with a model and a collection holding one item the way the
+    /// component manager stores them
+    fn add_entity(kv: &MemoryStore, shard: &str, entity: &str) {
+        kv.set_add(&shard_entities_key(shard), entity).unwrap();
+        kv.set(&owner_key(shard, entity), "bob", None).unwrap();
+        kv.set_add(&shard_users_key(shard), "bob").unwrap();
+        for (name, kind) in &[("position", TYPE_MODEL), ("cargo", TYPE_COLLECTION)] {
+            let key = component_key(shard, entity, name);
+            kv.list_add(&entity_components_key(shard, entity), name)
+                .unwrap();
+            kv.set_add(&component_entities_key(shard, name), entity)
+                .unwrap();
+            kv.set(&type_key(&key), kind, None).unwrap();
+            kv.atomic_add(&revision_key(&key), 1).unwrap();
+        }
+        kv.set(
+            &component_key(shard, entity, "position"),
+            r#"{"x":1}"#,
+            None,
+        )
+        .unwrap();
+        let cargo = component_key(shard, entity, "cargo");
+        let item_rid = format!("decs.components.{}.{}.cargo.1", shard, entity);
+        let item = item_key(&item_rid);
+        kv.list_add(&cargo, &item_rid).unwrap();
+        kv.atomic_add(&item_id_key(&cargo), 1).unwrap();
+        kv.set(&item, r#"{"mass":1}"#, Some(30)).unwrap();
+        kv.set(&type_key(&item), TYPE_MODEL, None).unwrap();
+        kv.atomic_add(&revision_key(&item), 1).unwrap();
+        kv.set_add(&shard_expiring_key(shard), &item_rid).unwrap();
+        incr_shard(kv, shard, 2).unwrap();
+    }
+
+    #[test]
+    fn test_delete_shard() {
+        let kv = MemoryStore::new();
+        put_shard(&kv, &shard("zone2", 5)).unwrap();
+        add_entity(&kv, "zone2", "ship2");
+        let kept = kv.keys();
+        put_shard(&kv, &shard("zone1", 5)).unwrap();
+        add_entity(&kv, "zone1", "ship1");
+
+        let err = delete_shard(&kv, "zone1", false).unwrap_err();
+        assert_eq!(err.to_string(), NOT_EMPTY);
+        assert_eq!(get_shard_details(&kv, "zone1").unwrap().current, 2);
+
+        let idx = get_shards(&kv)
+            .unwrap()
+            .iter()
+            .position(|s| s == "zone1")
+            .unwrap();
+        assert_eq!(delete_shard(&kv, "zone1", true).unwrap(), idx);
+        assert_eq!(get_shards(&kv).unwrap(), vec!["zone2".to_string()]);
+        assert_eq!(kv.keys(), kept);
+
+        let err = delete_shard(&kv, "zone1", true).unwrap_err();
+        assert_eq!(err.to_string(), NOT_FOUND);
+    }
+
+    #[test]
+    fn test_delete_empty_shard() {
+        let kv = MemoryStore::new();
+        put_shard(&kv, &shard("zone1", 5)).unwrap();
+        reserve_shard(&kv, "zone1", 1).unwrap();
+        incr_shard(&kv, "zone1", -1).unwrap();
+
+        delete_shard(&kv, "zone1", false).unwrap();
+        assert!(kv.keys().is_empty());
+    }
+
+    #[test]
+    fn test_recount_shard() {
+        let kv = MemoryStore::new();
+        put_shard(&kv, &shard("zone1", 5)).unwrap();
+        put_shard(&kv, &shard("zone2", 5)).unwrap();
+        add_entity(&kv, "zone1", "ship1");
+        add_entity(&kv, "zone1", "ship2");
+        add_entity(&kv, "zone2", "ship3");
+
+        // a lapsed item still counts until it is swept up
+        kv.del_key("decs:components:zone1:ship1:cargo:1").unwrap();
+        incr_shard(&kv, "zone1", 3).unwrap();
+        let (s, previous) = recount_shard(&kv, "zone1").unwrap();
+        assert_eq!((s.current, previous), (4, 7));
+        assert_eq!(get_shard_details(&kv, "zone1").unwrap().current, 4);
+
+        kv.del_key("decs:components:zone1:ship1:cargo:1:type")
+            .unwrap();
+        let (s, previous) = recount_shard(&kv, "zone1").unwrap();
+        assert_eq!((s.current, previous), (3, 4));
+        assert_eq!(get_shard_details(&kv, "zone2").unwrap().current, 2);
+
+        let err = recount_shard(&kv, "nowhere").unwrap_err();
+        assert_eq!(err.to_string(), NOT_FOUND);
+    }
+}
@@ -9,7 +9,7 @@
 //!
 
 use crate::store;
-use codec::gateway::ResourceIdentifier;
+use codec::gateway::{ResEvent, Rid};
 use codec::systemmgr::System;
 use decscloud_common as codec;
 use guest::prelude::*;
@@ -20,9 +20,7 @@ const REGISTRY_PONG_SUBJECT: &str = "decs.system.registry.replies";
 
 const GAMELOOP_SUFFIX: &str = ".gameloop";
 const GW_GET_PREFIX: &str = "get.decs.system";
-const GW_GET_COLLECTION: &str = "get.decs.systems";
 const GW_ACCESS_PREFIX: &str = "access.decs.system";
-const GW_GET_SINGLE_PREFIX: &str = "get.decs.system.";
 
 pub fn handle_timer(
     ctx: &CapabilitiesContext,
@@ -51,13 +49,13 @@ pub fn handle_message(
     let msg = msg.into().message;
     if let Some(msg) = msg {
         if msg.subject == REGISTRY_PONG_SUBJECT {
-            handle_registration(&ctx, &msg)?;
+            handle_registration(ctx, &msg)?;
         } else if msg.subject.starts_with(GW_GET_PREFIX) {
-            handle_get(&ctx, &msg)?;
+            handle_get(ctx, &msg)?;
         } else if msg.subject.starts_with(GW_ACCESS_PREFIX) {
-            handle_access(&ctx, &msg)?;
+            handle_access(ctx, &msg)?;
         } else if msg.subject.ends_with(GAMELOOP_SUFFIX) {
-            handle_gameloop(&ctx, &msg)?;
+            handle_gameloop(ctx, &msg)?;
         }
         Ok(vec![])
     } else {
@@ -65,7 +63,7 @@ pub fn handle_message(
     }
 }
 
-// Upon receipt of a game loop tick, the system manager must
+// Upon receipt of a game loop tick for a shard whose systems run (i.e. not paused), the system manager must
 //   for each discovered system:
 //     determine if it is the right time to emit a message for the given system (based on system FPS desire)
 //     emit an entity frame message for each entity that has all of that system's components
@@ -74,6 +72,9 @@ pub fn handle_message(
 // query the values of any components it needs when it gets an entity frame
 fn handle_gameloop(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
     let gtick: codec::timer::GameLoopTick = serde_json::from_slice(&msg.body)?;
+    if !gtick.state.runs_systems() {
+        return Ok(vec![]);
+    }
     let shard = gtick.shard;
     let systems = store::get_systems(ctx)?;
     let systemlist = store::get_system_list(ctx, systems)?;
@@ -112,65 +113,58 @@ fn handle_registration(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage
 }
 
 fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let result = json!({
-        "result" : {
-            "get" : true
-        }
-    });
+    let result = codec::gateway::access_result(true, None);
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
 }
 
 fn handle_get(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    if msg.subject == GW_GET_COLLECTION {
-        get_collection(ctx, msg)
-    } else if msg.subject.starts_with(GW_GET_SINGLE_PREFIX) {
-        get_single(ctx, msg)
-    } else {
-        Ok(vec![])
+    match msg.subject["get.".len()..].parse::<Rid>() {
+        Ok(Rid::Systems) => get_collection(ctx, msg),
+        Ok(Rid::System(ref name)) => get_single(ctx, msg, name),
+        Ok(other) => reply_invalid_params(ctx, msg, &format!("not a system resource: {}", other)),
+        Err(e) => reply_invalid_params(ctx, msg, &e.to_string()),
+    }
+}
+
+fn reply_invalid_params(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    err: &str,
+) -> CallResult {
+    if !msg.reply_to.is_empty() {
+        ctx.msg().publish(
+            &msg.reply_to,
+            None,
+            &serde_json::to_vec(&codec::gateway::error_invalid_params(err))?,
+        )?;
     }
+    Ok(vec![])
 }
 
 fn get_collection(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
     let syslist = store::get_systems(ctx)?;
-    let rids: Vec<_> = syslist
-        .iter()
-        .map(|s| codec::gateway::ResourceIdentifier {
-            rid: format!("decs.system.{}", s),
-        })
-        .collect();
-    let result = json!({
-        "result": {
-            "collection": rids
-        }
-    });
+    let result = codec::gateway::collection_result(syslist.into_iter().map(Rid::System));
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
 }
 
-fn get_single(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let tokens: Vec<&str> = msg.subject.split('.').collect();
-    if tokens.len() != 4 {
-        // get.decs.system.xxx
-        Err("incorrectly formatted single-system get request".into())
-    } else {
-        let s_name = tokens[3];
-        let system = store::get_system_details(ctx, s_name)?;
-        let result = json!({
-            "result": {
-                "model": {
-                    "components" : system.components.join(","), // resgate only allows primitives or RIDs
-                    "framerate": system.framerate,
-                    "name": system.name,
-                }
-            }
-        });
-        ctx.msg()
-            .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
-        Ok(vec![])
-    }
+fn get_single(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    s_name: &str,
+) -> CallResult {
+    let system = store::get_system_details(ctx, s_name)?;
+    let result = codec::gateway::model_result(json!({
+        "components" : system.components.join(","), // resgate only allows primitives or RIDs
+        "framerate": system.framerate,
+        "name": system.name,
+    }));
+    ctx.msg()
+        .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
+    Ok(vec![])
 }
 
 fn system_modulus(framerate: u32, elapsed_ms: u32) -> u32 {
@@ -178,37 +172,34 @@ fn system_modulus(framerate: u32, elapsed_ms: u32) -> u32 {
 }
 
 fn should_publish(framerate: u32, elapsed_ms: u32, seq_no: u64) -> bool {
-    seq_no % u64::from(system_modulus(framerate, elapsed_ms)) == 0
+    seq_no.is_multiple_of(u64::from(system_modulus(framerate, elapsed_ms)))
 }
 
 fn publish_collection_add(ctx: &CapabilitiesContext, system: &System, idx: usize) -> Result<()> {
-    let subject = "event.decs.systems.add";
-    let item = format!("decs.system.{}", system.name);
-
-    let rid = ResourceIdentifier { rid: item };
-    let out = json!({
-        "value" : rid,
-        "idx": idx
-    });
-    ctx.log(&format!("Publishing Collection Add, subject: {}", subject));
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    Ok(())
+    let item = Rid::System(system.name.clone()).to_string();
+    let event = ResEvent::add(&Rid::Systems.to_string(), &item, idx);
+    ctx.log(&format!(
+        "Publishing Collection Add, subject: {}",
+        event.subject
+    ));
+    ctx.msg().publish(&event.subject, None, &event.body())
 }
 
 fn publish_model_change(ctx: &CapabilitiesContext, system: &System) -> Result<()> {
-    let item = format!("decs.system.{}", system.name);
-    let subject = format!("event.{}.change", item);
-
-    let out = json!({ "values": {
-        "components": system.components.join(","),
-        "framerate": system.framerate,
-        "name" : system.name
-    }});
-    ctx.log(&format!("Publishing Model Change, subject: {}", subject));
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    Ok(())
+    let item = Rid::System(system.name.clone()).to_string();
+    let event = ResEvent::change(
+        &item,
+        json!({
+            "components": system.components.join(","),
+            "framerate": system.framerate,
+            "name" : system.name
+        }),
+    );
+    ctx.log(&format!(
+        "Publishing Model Change, subject: {}",
+        event.subject
+    ));
+    ctx.msg().publish(&event.subject, None, &event.body())
 }
 
 #[cfg(test)]
@@ -25,8 +25,8 @@ pub(crate) fn get_systems(ctx: &CapabilitiesContext) -> Result<Vec<String>> {
 }
 
 fn index_of(ctx: &CapabilitiesContext, listkey: &str, item: &str) -> Result<usize> {
-    let members = ctx.kv().list_range(&listkey, 0, -1)?;
-    Ok(members.iter().position(|s| *s == item).unwrap_or_else(|| 0))
+    let members = ctx.kv().list_range(listkey, 0, -1)?;
+    Ok(members.iter().position(|s| *s == item).unwrap_or(0))
 }
 
 pub(crate) fn get_system_list(
@@ -5,14 +5,20 @@
 //!    get.decs.user.* ([GW GET]/api/decs/user/{user-name})
 //!    access.decs.user.*
 //!    access.decs.users
-//!    call.decs.user.*.set (updates a user)
-//!    call.decs.users.add (creates a user)
+//!    call.decs.user.*.set (updates a user's profile, by the user or an admin)
+//!    call.decs.user.*.changePassword (replaces a user's password, given the old one unless admin)
+//!    call.decs.user.*.delete (deletes a user, by the user or an admin)
+//!    call.decs.user.*.grantRole (grants a role to a user, admin only)
+//!    call.decs.user.*.revokeRole (revokes a role from a user, admin only)
+//!    call.decs.users.add (creates a user, with roles only when called by the server side)
+//!    auth.decs.users.login (authenticates a connection with email and password)
+//!    auth.decs.users.logout (clears a connection's token)
 //!
 
 use crate::store;
 use decscloud_common as codec;
-use decscloud_common::gateway::{ResProtocolRequest, ResourceIdentifier};
-use decscloud_common::users::User;
+use decscloud_common::gateway::{ErrorCode, ResEvent, ResProtocolRequest, Rid};
+use decscloud_common::users::{AccessToken, User, ROLE_ADMIN};
 use guest::prelude::*;
 
 /// Examine the subject of the message and invoke the appopriate function
@@ -23,13 +29,49 @@ pub fn handle_message(
     let msg = msg.into().message;
     if let Some(msg) = msg {
         match ResProtocolRequest::from(msg.subject.as_str()) {
-            ResProtocolRequest::Get(_) if msg.subject.ends_with("users") => {
-                handle_get_collection(ctx, &msg)
-            }
+            ResProtocolRequest::Get(ref rid) => match rid.parse::<Rid>() {
+                Ok(Rid::Users) => handle_get_collection(ctx, &msg),
+                Ok(Rid::User(ref id)) => handle_get_single(ctx, id, &msg),
+                other => reply_invalid_rid(ctx, &msg, other),
+            },
             ResProtocolRequest::Add(_) => handle_create(ctx, &msg),
-            ResProtocolRequest::Get(rid) => handle_get_single(ctx, &rid, &msg),
-            ResProtocolRequest::Set(_) => handle_set(ctx, &msg),
-            ResProtocolRequest::Access(_) => handle_access(ctx, &msg),
+            ResProtocolRequest::Set(ref rid) => match rid.parse::<Rid>() {
+                Ok(Rid::User(ref id)) => handle_set(ctx, id, &msg),
+                other => reply_invalid_rid(ctx, &msg, other),
+            },
+            ResProtocolRequest::Delete(ref rid) => match rid.parse::<Rid>() {
+                Ok(Rid::User(ref id)) => handle_delete(ctx, id, &msg),
+                other => reply_invalid_rid(ctx, &msg, other),
+            },
+            ResProtocolRequest::Call(ref rid, ref method) if method == "changePassword" => {
+                match rid.parse::<Rid>() {
+                    Ok(Rid::User(ref id)) => handle_change_password(ctx, id, &msg),
+                    other => reply_invalid_rid(ctx, &msg, other),
+                }
+            }
+            ResProtocolRequest::Call(ref rid, ref method)
+                if method == "grantRole" || method == "revokeRole" =>
+            {
+                match rid.parse::<Rid>() {
+                    Ok(Rid::User(ref id)) => handle_role_change(ctx, id, method, &msg),
+                    other => reply_invalid_rid(ctx, &msg, other),
+                }
+            }
+            ResProtocolRequest::Access(ref rid) => handle_access(ctx, rid, &msg),
+            ResProtocolRequest::Auth(ref rid, ref method) if *rid == Rid::Users.to_string() => {
+                match method.as_str() {
+                    "login" => handle_login(ctx, &msg),
+                    "logout" => handle_logout(ctx, &msg),
+                    _ => reply(
+                        ctx,
+                        &msg,
+                        &codec::gateway::error_method_not_found(&format!(
+                            "No such auth method: {}",
+                            method
+                        )),
+                    ),
+                }
+            }
             _ => Err("unknown service request format".into()),
         }
     } else {
@@ -45,21 +87,180 @@ pub fn handle_message(
 ///   "cid" : ... connection id ...
 /// }
 /// ```
-fn handle_set(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let user = extract_user_from_set(&msg.body)?;
+/// Only the user's email can be set; `pass` and `roles` in the params are ignored. Only the
+/// user themselves or an admin may change a user's email
+fn handle_set(ctx: &CapabilitiesContext, id: &str, msg: &messaging::BrokerMessage) -> CallResult {
+    if !may_manage_user(&msg.body, id) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only the user or an admin may change a user"),
+        );
+    }
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
     ctx.log(&format!(
         "Handling set request: {}, reply-to: {}",
         msg.subject, msg.reply_to
     ));
-    update_user(ctx, &user)
+    let email = v["params"]["email"].as_str().unwrap_or_default();
+    if let Err(e) = validate_email(email) {
+        let mut fields = FieldErrors::new();
+        fields.insert("email".to_string(), json!(e));
+        return reply(ctx, msg, &error_invalid_fields(fields));
+    }
+    match store::update_user(ctx.kv(), id, email) {
+        Ok(user) => {
+            publish_model_change(ctx, &user)?;
+            reply(ctx, msg, &codec::gateway::success_response())
+        }
+        Err(ref e) if e.to_string() == store::NOT_FOUND => {
+            reply(ctx, msg, &codec::gateway::error_not_found("No such user"))
+        }
+        Err(ref e) if e.to_string() == store::EMAIL_TAKEN => reply(
+            ctx,
+            msg,
+            &codec::gateway::error_conflict(store::EMAIL_TAKEN),
+        ),
+        Err(e) => Err(e),
+    }
+}
+
+/// Grants or revokes a role, given as `{ "params": { "role": ... } }`. Only admins may change
+/// roles. The user's existing tokens keep their old roles until they log in again
+fn handle_role_change(
+    ctx: &CapabilitiesContext,
+    id: &str,
+    method: &str,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    if !codec::users::request_has_role(&msg.body, ROLE_ADMIN) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only admins may change roles"),
+        );
+    }
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let role = v["params"]["role"].as_str().unwrap_or_default();
+    if let Err(e) = validate_id(role) {
+        let mut fields = FieldErrors::new();
+        fields.insert("role".to_string(), json!(e));
+        return reply(ctx, msg, &error_invalid_fields(fields));
+    }
+    ctx.log(&format!("Handling {} of {} for user {}", method, role, id));
+
+    let changed = if method == "grantRole" {
+        store::grant_role(ctx.kv(), id, role)
+    } else {
+        store::revoke_role(ctx.kv(), id, role)
+    };
+    match changed {
+        Ok(Some(user)) => {
+            publish_model_change(ctx, &user)?;
+            reply(ctx, msg, &codec::gateway::success_response())
+        }
+        Ok(None) => reply(ctx, msg, &codec::gateway::success_response()),
+        Err(ref e) if e.to_string() == store::NOT_FOUND => {
+            reply(ctx, msg, &codec::gateway::error_not_found("No such user"))
+        }
+        Err(e) => Err(e),
+    }
 }
 
+/// Deletes a user. Only the user themselves or an admin may delete a user
+fn handle_delete(
+    ctx: &CapabilitiesContext,
+    id: &str,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    if !may_manage_user(&msg.body, id) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied("Only the user or an admin may delete a user"),
+        );
+    }
+    ctx.log(&format!("Handling delete request for user {}", id));
+    match store::delete_user(ctx.kv(), id) {
+        Ok(idx) => {
+            publish_event(ctx, &ResEvent::remove(&Rid::Users.to_string(), idx))?;
+            publish_event(
+                ctx,
+                &ResEvent::delete(&Rid::User(id.to_string()).to_string()),
+            )?;
+            reply(ctx, msg, &codec::gateway::success_response())
+        }
+        Err(ref e) if e.to_string() == store::NOT_FOUND => {
+            reply(ctx, msg, &codec::gateway::error_not_found("No such user"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a user's password. Only the user themselves or an admin may do so, and unless
+/// an admin is resetting it the request must carry the current password:
+/// ```
+/// {
+///   "params" : { "oldPass": ..., "newPass": ... },
+///   ...
+/// }
+/// ```
+fn handle_change_password(
+    ctx: &CapabilitiesContext,
+    id: &str,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    if !may_manage_user(&msg.body, id) {
+        return reply(
+            ctx,
+            msg,
+            &codec::gateway::error_access_denied(
+                "Only the user or an admin may change a user's password",
+            ),
+        );
+    }
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let is_admin = AccessToken::from_request(&msg.body).is_some_and(|t| t.is_admin());
+    let old_pass = match v["params"]["oldPass"].as_str() {
+        None if is_admin => None,
+        old_pass => Some(old_pass.unwrap_or_default()),
+    };
+    let new_pass = v["params"]["newPass"].as_str().unwrap_or_default();
+    ctx.log(&format!("Handling password change for user {}", id));
+
+    let result = if new_pass.is_empty() {
+        codec::gateway::error_invalid_params("New password must not be empty")
+    } else {
+        match store::change_password(ctx.kv(), id, old_pass, new_pass) {
+            Ok(true) => codec::gateway::success_response(),
+            Ok(false) => codec::gateway::error_access_denied("Invalid password"),
+            Err(ref e) if e.to_string() == store::NOT_FOUND => {
+                codec::gateway::error_not_found("No such user")
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Creates a user from the `email`, `pass` and optional `id` params. When no id is supplied,
+/// one is generated by the server. Users created by clients start without roles; only a
+/// request made by the server side, which has no connection, may give a new user `roles`,
+/// which is how the first admin is bootstrapped
 fn handle_create(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let user = extract_user_from_set(&msg.body)?;
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
     ctx.log(&format!(
         "Handling new request: {}, reply-to: {}",
         msg.subject, msg.reply_to
     ));
+    let mut user = match validate_new_user(&v["params"]) {
+        Ok(user) => user,
+        Err(fields) => return reply(ctx, msg, &error_invalid_fields(fields)),
+    };
+    if user.id.is_empty() {
+        user.id = store::next_user_id(ctx.kv())?;
+    }
+    user.roles = server_side_roles(&v);
     create_user(ctx, &user, msg)
 }
 
@@ -68,63 +269,133 @@ fn create_user(
     user: &User,
     msg: &messaging::BrokerMessage,
 ) -> CallResult {
-    match store::create_user(ctx, &user) {
+    match store::create_user(ctx.kv(), user) {
         Ok((pos, resid)) => {
-            publish_collection_add(ctx, &user, pos)?;
+            publish_collection_add(ctx, user, pos)?;
             if !msg.reply_to.is_empty() {
-                let result = json!({ "result": resid });
+                let result = codec::gateway::resource_result(&resid);
                 ctx.msg()
                     .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
             }
             Ok(vec![])
         }
-        Err(e) => Err(e),
-    }
-}
-
-fn update_user(ctx: &CapabilitiesContext, user: &User) -> CallResult {
-    match store::update_user(ctx, &user) {
-        Ok(_) => publish_model_change(ctx, &user),
+        Err(ref e) if e.to_string() == store::ID_TAKEN || e.to_string() == store::EMAIL_TAKEN => {
+            reply(ctx, msg, &codec::gateway::error_conflict(&e.to_string()))
+        }
         Err(e) => Err(e),
     }
 }
 
 fn publish_collection_add(ctx: &CapabilitiesContext, user: &User, pos: usize) -> CallResult {
-    let subject = "event.decs.users.add";
-    let item = format!("decs.user.{}", user.id);
-    let rid = ResourceIdentifier { rid: item };
-    let out = json!({
-        "value": rid,
-        "idx": pos
-    });
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
-    Ok(vec![])
+    let item = Rid::User(user.id.clone()).to_string();
+    publish_event(ctx, &ResEvent::add(&Rid::Users.to_string(), &item, pos))
 }
 
 fn publish_model_change(ctx: &CapabilitiesContext, user: &User) -> CallResult {
-    let item = format!("decs.user.{}", user.id);
-    let subject = format!("event.{}.change", item);
+    let item = Rid::User(user.id.clone()).to_string();
+    publish_event(ctx, &ResEvent::change(&item, json!(user.profile())))
+}
 
-    let out = json!({ "values": user });
-    ctx.msg()
-        .publish(&subject, None, &serde_json::to_vec(&out)?)?;
+fn publish_event(ctx: &CapabilitiesContext, event: &ResEvent) -> CallResult {
+    ctx.msg().publish(&event.subject, None, &event.body())?;
     Ok(vec![])
 }
 
-fn extract_user_from_set(body: &[u8]) -> Result<User> {
-    let v: serde_json::Value = serde_json::from_slice(body)?;
-    let user = &v["params"];
-    Ok(serde_json::from_value(user.clone())?)
+/// Validates the params of a users.add request, returning either the user to create or
+/// the problems found, keyed by field name
+fn validate_new_user(params: &serde_json::Value) -> std::result::Result<User, FieldErrors> {
+    let mut errors = FieldErrors::new();
+    let id = match &params["id"] {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(id) => {
+            if let Err(e) = validate_id(id) {
+                errors.insert("id".to_string(), json!(e));
+            }
+            id.clone()
+        }
+        _ => {
+            errors.insert("id".to_string(), json!("must be a string"));
+            String::new()
+        }
+    };
+    let email = params["email"].as_str().unwrap_or_default();
+    if let Err(e) = validate_email(email) {
+        errors.insert("email".to_string(), json!(e));
+    }
+    let pass = params["pass"].as_str().unwrap_or_default();
+    if pass.is_empty() {
+        errors.insert("pass".to_string(), json!("must not be empty"));
+    }
+
+    if errors.is_empty() {
+        Ok(User {
+            email: email.to_string(),
+            pass: pass.to_string(),
+            id,
+            roles: vec![],
+        })
+    } else {
+        Err(errors)
+    }
 }
 
-fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
-    let result = json!({
-        "result" : {
-            "get" : true,
-            "call" : "*"
+type FieldErrors = serde_json::Map<String, serde_json::Value>;
+
+const MAX_ID_LEN: usize = 64;
+const MAX_EMAIL_LEN: usize = 254;
+
+/// User ids become part of the `decs.user.{id}` RID, so they're limited to characters that
+/// can't be confused with RID separators or wildcards
+fn validate_id(id: &str) -> std::result::Result<(), &'static str> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        Err("must be between 1 and 64 characters")
+    } else if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Err("may only contain letters, digits, '-' and '_'")
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_email(email: &str) -> std::result::Result<(), &'static str> {
+    if email.is_empty() {
+        return Err("must not be empty");
+    }
+    if email.len() > MAX_EMAIL_LEN || email.chars().any(char::is_whitespace) {
+        return Err("is not a valid email address");
+    }
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|part| !part.is_empty()) =>
+        {
+            Ok(())
         }
-    });
+        _ => Err("is not a valid email address"),
+    }
+}
+
+/// Generates an invalidParams error listing the problem with each offending field
+fn error_invalid_fields(fields: FieldErrors) -> serde_json::Value {
+    codec::gateway::error_response_with_data(
+        ErrorCode::InvalidParams,
+        "Invalid parameters",
+        json!({ "fields": fields }),
+    )
+}
+
+/// Anyone may read users and create new ones, but only the user themselves or an admin may
+/// call methods on a user, and only admins may change roles
+fn handle_access(
+    ctx: &CapabilitiesContext,
+    rid: &str,
+    msg: &messaging::BrokerMessage,
+) -> CallResult {
+    let result = codec::gateway::access_result(true, allowed_calls(&msg.body, rid));
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
@@ -132,19 +403,9 @@ fn handle_access(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> C
 
 fn handle_get_collection(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
     // If we don't have any shards, create "the void" by default
-    let userlist = store::get_users(ctx)?;
+    let userlist = store::get_users(ctx.kv())?;
 
-    let rids: Vec<_> = userlist
-        .iter()
-        .map(|s| codec::gateway::ResourceIdentifier {
-            rid: format!("decs.user.{}", s),
-        })
-        .collect();
-    let result = json!({
-        "result": {
-            "collection": rids
-        }
-    });
+    let result = codec::gateway::collection_result(userlist.into_iter().map(Rid::User));
     ctx.msg()
         .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
     Ok(vec![])
@@ -152,16 +413,12 @@ fn handle_get_collection(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessa
 
 fn handle_get_single(
     ctx: &CapabilitiesContext,
-    rid: &str,
+    id: &str,
     msg: &messaging::BrokerMessage,
 ) -> CallResult {
-    match store::get_user_details(ctx, rid) {
+    match store::get_user_details(ctx.kv(), id) {
         Ok(user) => {
-            let result = json!({
-                "result": {
-                    "model": user
-                }
-            });
+            let result = codec::gateway::model_result(json!(user.profile()));
             ctx.msg()
                 .publish(&msg.reply_to, None, &serde_json::to_vec(&result)?)?;
         }
@@ -184,3 +441,218 @@ fn handle_get_single(
     }
     Ok(vec![])
 }
+
+/// Answers a request whose resource ID is malformed or not a user resource
+fn reply_invalid_rid(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    rid: std::result::Result<Rid, codec::gateway::RidError>,
+) -> CallResult {
+    let err = match rid {
+        Ok(other) => format!("not a user resource: {}", other),
+        Err(e) => e.to_string(),
+    };
+    reply(ctx, msg, &codec::gateway::error_invalid_params(&err))
+}
+
+/// Authenticates the connection making the request. The RES auth request payload looks as follows:
+/// ```
+/// {
+///   "params" : { "email": ..., "pass": ... },
+///   "cid" : ... connection id ...,
+///   "header" : ... HTTP headers of the connection ...,
+///   "token" : ... current access token ...
+/// }
+/// ```
+/// On success the connection's access token is set with a `conn.{cid}.token` event, after
+/// which RESgate includes it in every request the connection makes
+fn handle_login(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let cid = v["cid"].as_str().unwrap_or_default();
+    let email = v["params"]["email"].as_str().unwrap_or_default();
+    let pass = v["params"]["pass"].as_str().unwrap_or_default();
+    ctx.log(&format!("Handling login request for connection {}", cid));
+
+    let result = if cid.is_empty() {
+        codec::gateway::error_invalid_params("Auth request has no connection ID")
+    } else {
+        match store::authenticate(ctx.kv(), email, pass)? {
+            Some(user) => {
+                let token = issue_token(&user);
+                publish_event(ctx, &ResEvent::connection_token(cid, json!(token)))?;
+                codec::gateway::success_response()
+            }
+            None => codec::gateway::error_access_denied("Invalid email or password"),
+        }
+    };
+    reply(ctx, msg, &result)
+}
+
+/// Clears the access token of the connection making the request
+fn handle_logout(ctx: &CapabilitiesContext, msg: &messaging::BrokerMessage) -> CallResult {
+    let v: serde_json::Value = serde_json::from_slice(&msg.body)?;
+    let result = match v["cid"].as_str() {
+        Some(cid) if !cid.is_empty() => {
+            publish_event(ctx, &ResEvent::connection_token(cid, json!(null)))?;
+            codec::gateway::success_response()
+        }
+        _ => codec::gateway::error_invalid_params("Auth request has no connection ID"),
+    };
+    reply(ctx, msg, &result)
+}
+
+/// The roles asked for in a users.add request, honoured only when the request comes from
+/// the server side rather than through RESgate, which always passes the connection id
+fn server_side_roles(request: &serde_json::Value) -> Vec<String> {
+    if !request["cid"].is_null() {
+        return vec![];
+    }
+    request["params"]["roles"]
+        .as_array()
+        .map(|roles| {
+            roles
+                .iter()
+                .filter_map(|r| r.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Indicates whether a request's token belongs to the given user or to an admin
+fn may_manage_user(body: &[u8], id: &str) -> bool {
+    AccessToken::from_request(body).is_some_and(|t| t.user_id == id || t.is_admin())
+}
+
+/// Determines which methods the request's token may call on the given resource
+fn allowed_calls(body: &[u8], rid: &str) -> Option<&'static str> {
+    match rid.parse::<Rid>() {
+        Ok(Rid::Users) => Some("add"),
+        Ok(Rid::User(ref id)) => match AccessToken::from_request(body) {
+            Some(ref t) if t.is_admin() => Some("*"),
+            Some(ref t) if t.user_id == *id => Some("set,delete,changePassword"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn issue_token(user: &User) -> AccessToken {
+    AccessToken {
+        user_id: user.id.clone(),
+        roles: user.roles.clone(),
+    }
+}
+
+fn reply(
+    ctx: &CapabilitiesContext,
+    msg: &messaging::BrokerMessage,
+    result: &serde_json::Value,
+) -> CallResult {
+    if !msg.reply_to.is_empty() {
+        ctx.msg()
+            .publish(&msg.reply_to, None, &serde_json::to_vec(result)?)?;
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        allowed_calls, may_manage_user, server_side_roles, validate_email, validate_id,
+        validate_new_user,
+    };
+
+    #[test]
+    fn test_server_side_roles() {
+        let params = json!({"email": "root@example.com", "pass": "pw", "roles": ["admin"]});
+        assert_eq!(
+            server_side_roles(&json!({ "params": params })),
+            vec!["admin".to_string()]
+        );
+        // anything from a client connection, signed in or not, gets no roles
+        assert!(server_side_roles(&json!({ "params": params, "cid": "abc" })).is_empty());
+        assert!(server_side_roles(&json!({ "params": {} })).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_calls() {
+        let request =
+            |token: serde_json::Value| serde_json::to_vec(&json!({ "token": token })).unwrap();
+        let admin = request(json!({"user_id": "alice", "roles": ["admin"]}));
+        let bob = request(json!({"user_id": "bob"}));
+        let anonymous = request(json!(null));
+
+        assert_eq!(allowed_calls(&anonymous, "decs.users"), Some("add"));
+        assert_eq!(allowed_calls(&admin, "decs.user.bob"), Some("*"));
+        assert_eq!(
+            allowed_calls(&bob, "decs.user.bob"),
+            Some("set,delete,changePassword")
+        );
+        assert_eq!(allowed_calls(&bob, "decs.user.alice"), None);
+        assert_eq!(allowed_calls(&anonymous, "decs.user.bob"), None);
+    }
+
+    #[test]
+    fn test_may_manage_user() {
+        let request = |token: serde_json::Value| {
+            serde_json::to_vec(&json!({ "params": {}, "token": token })).unwrap()
+        };
+        assert!(may_manage_user(&request(json!({"user_id": "bob"})), "bob"));
+        assert!(may_manage_user(
+            &request(json!({"user_id": "alice", "roles": ["admin"]})),
+            "bob"
+        ));
+        assert!(!may_manage_user(
+            &request(json!({"user_id": "alice"})),
+            "bob"
+        ));
+        assert!(!may_manage_user(&request(json!(null)), "bob"));
+    }
+
+    #[test]
+    fn test_validate_new_user() {
+        let user = validate_new_user(&json!({"email": "bob@example.com", "pass": "pw"})).unwrap();
+        assert_eq!(user.id, "");
+        let user =
+            validate_new_user(&json!({"id": "bob", "email": "bob@example.com", "pass": "pw"}))
+                .unwrap();
+        assert_eq!(user.id, "bob");
+
+        let errors =
+            validate_new_user(&json!({"id": "bob.*", "email": "bob", "pass": ""})).unwrap_err();
+        assert_eq!(
+            errors.keys().collect::<Vec<_>>(),
+            vec!["email", "id", "pass"]
+        );
+        let errors = validate_new_user(&json!({"id": 7, "email": "bob@example.com", "pass": "pw"}))
+            .unwrap_err();
+        assert_eq!(errors["id"], "must be a string");
+    }
+
+    #[test]
+    fn test_validate_id() {
+        assert!(validate_id("bob_the-2nd").is_ok());
+        assert!(validate_id("").is_err());
+        assert!(validate_id("bob.smith").is_err());
+        assert!(validate_id("bob>").is_err());
+        assert!(validate_id(&"x".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_validate_email() {
+        assert!(validate_email("bob@example.com").is_ok());
+        assert!(validate_email("bob+decs@mail.example.co.uk").is_ok());
+        for bad in &[
+            "",
+            "bob",
+            "@example.com",
+            "bob@",
+            "bob@localhost",
+            "bob@@example.com",
+            "bob@example..com",
+            "bob smith@example.com",
+        ] {
+            assert!(validate_email(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
+}
@@ -1,47 +1,176 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use blake2::digest::consts::U16;
+use blake2::{Blake2b, Digest};
 use decscloud_common as codec;
-use guest::prelude::*;
+use decscloud_common::gateway::Rid;
+use decscloud_common::kv::KeyValue;
 
 const USERS_KEY: &str = "decs:users";
+const EMAILS_KEY: &str = "decs:users:emails";
+const SALT_SEQ_KEY: &str = "decs:users:salt_seq";
+const ID_SEQ_KEY: &str = "decs:users:id_seq";
 pub(crate) const NOT_FOUND: &str = "Not found";
+pub(crate) const ID_TAKEN: &str = "A user with that id already exists";
+pub(crate) const EMAIL_TAKEN: &str = "A user with that email already exists";
 
-pub(crate) fn get_users(ctx: &CapabilitiesContext) -> Result<Vec<String>> {
-    ctx.kv().list_range(USERS_KEY, 0, -1)
+fn user_key(id: &str) -> String {
+    format!("decs:user:{}", id)
 }
 
+/// Key of the `email -> id` index entry. Emails are compared case-insensitively
+fn email_key(email: &str) -> String {
+    format!("decs:users:email:{}", normalize_email(email))
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Reserves an email address for a user. The reservation is made by adding the address to the
+/// `decs:users:emails` set, so two concurrent requests can't both claim it
+fn claim_email(
+    kv: &impl KeyValue,
+    email: &str,
+    id: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if kv.set_add(EMAILS_KEY, &normalize_email(email))? == 0 {
+        return Err(EMAIL_TAKEN.into());
+    }
+    kv.set(&email_key(email), id, None)?;
+    Ok(())
+}
+
+fn release_email(kv: &impl KeyValue, email: &str) -> codec::kv::Result<()> {
+    kv.set_remove(EMAILS_KEY, &normalize_email(email))?;
+    kv.del_key(&email_key(email))
+}
+
+pub(crate) fn get_users(kv: &impl KeyValue) -> codec::kv::Result<Vec<String>> {
+    kv.list_range(USERS_KEY, 0, -1)
+}
+
+/// Generates an id for a user created without one
+pub(crate) fn next_user_id(kv: &impl KeyValue) -> codec::kv::Result<String> {
+    codec::ids::next_uuid(kv, ID_SEQ_KEY)
+}
+
+/// Creates a user with the roles given. No role is ever granted implicitly; the first
+/// admin has to be created with the role by the server side
 pub(crate) fn create_user(
-    ctx: &CapabilitiesContext,
+    kv: &impl KeyValue,
     user: &codec::users::User,
 ) -> std::result::Result<(usize, String), Box<dyn std::error::Error>> {
-    let user_key = format!("decs:user:{}", user.id);
-    let user_json = serde_json::to_string(&user)?;
-    ctx.kv().set(&user_key, &user_json, None)?;
-    ctx.kv().list_add(USERS_KEY, &user.id)?;
+    if kv.exists(&user_key(&user.id))? {
+        return Err(ID_TAKEN.into());
+    }
+    claim_email(kv, &user.email, &user.id)?;
+    let stored = codec::users::User {
+        pass: hash_password(kv, &user.id, &user.pass)?,
+        email: user.email.clone(),
+        id: user.id.clone(),
+        roles: user.roles.clone(),
+    };
+    let user_json = serde_json::to_string(&stored)?;
+    kv.set(&user_key(&user.id), &user_json, None)?;
+    kv.list_add(USERS_KEY, &user.id)?;
 
-    let users = ctx.kv().list_range(USERS_KEY, 0, -1)?;
+    let users = kv.list_range(USERS_KEY, 0, -1)?;
     match users.iter().position(|s| *s == user.id) {
-        Some(p) => Ok((p, format!("decs.user.{}", user.id))),
+        Some(p) => Ok((p, Rid::User(user.id.clone()).to_string())),
         None => Err("item not in set".into()),
     }
 }
 
-/// Creates or sets a user. Returns a boolean indicating if the user previously existed
+/// Updates the email of an existing user. The stored password hash and roles are kept as is;
+/// they can only be changed through `change_password` and `grant_role`/`revoke_role`
 pub(crate) fn update_user(
-    ctx: &CapabilitiesContext,
-    user: &codec::users::User,
-) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let user_key = format!("decs:user:{}", user.id);
+    kv: &impl KeyValue,
+    id: &str,
+    email: &str,
+) -> std::result::Result<codec::users::User, Box<dyn std::error::Error>> {
+    let mut user = get_user_details(kv, id)?;
+    if normalize_email(&user.email) != normalize_email(email) {
+        claim_email(kv, email, &user.id)?;
+        release_email(kv, &user.email)?;
+    }
+    user.email = email.to_string();
     let user_json = serde_json::to_string(&user)?;
-    ctx.kv().set(&user_key, &user_json, None)?;
-    Ok(())
+    kv.set(&user_key(&user.id), &user_json, None)?;
+    Ok(user)
+}
+
+/// Replaces a user's password, provided the old one is correct. Without an old password,
+/// e.g. for an admin resetting it, the password is replaced regardless. Returns a boolean
+/// indicating whether the password was changed
+pub(crate) fn change_password(
+    kv: &impl KeyValue,
+    id: &str,
+    old_pass: Option<&str>,
+    new_pass: &str,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let mut user = get_user_details(kv, id)?;
+    if old_pass.is_some_and(|old_pass| !verify_password(&user.pass, old_pass)) {
+        return Ok(false);
+    }
+    user.pass = hash_password(kv, id, new_pass)?;
+    let user_json = serde_json::to_string(&user)?;
+    kv.set(&user_key(id), &user_json, None)?;
+    Ok(true)
+}
+
+/// Grants a role to a user. Returns the updated user, or `None` if they already held the role
+pub(crate) fn grant_role(
+    kv: &impl KeyValue,
+    id: &str,
+    role: &str,
+) -> std::result::Result<Option<codec::users::User>, Box<dyn std::error::Error>> {
+    let mut user = get_user_details(kv, id)?;
+    if user.roles.iter().any(|r| r == role) {
+        return Ok(None);
+    }
+    user.roles.push(role.to_string());
+    kv.set(&user_key(id), &serde_json::to_string(&user)?, None)?;
+    Ok(Some(user))
+}
+
+/// Revokes a role from a user. Returns the updated user, or `None` if they didn't hold the role
+pub(crate) fn revoke_role(
+    kv: &impl KeyValue,
+    id: &str,
+    role: &str,
+) -> std::result::Result<Option<codec::users::User>, Box<dyn std::error::Error>> {
+    let mut user = get_user_details(kv, id)?;
+    if !user.roles.iter().any(|r| r == role) {
+        return Ok(None);
+    }
+    user.roles.retain(|r| r != role);
+    kv.set(&user_key(id), &serde_json::to_string(&user)?, None)?;
+    Ok(Some(user))
+}
+
+/// Deletes a user along with its index entries. Returns the index the user occupied in the
+/// `decs:users` collection before removal
+pub(crate) fn delete_user(
+    kv: &impl KeyValue,
+    id: &str,
+) -> std::result::Result<usize, Box<dyn std::error::Error>> {
+    let user = get_user_details(kv, id)?;
+    let idx = get_users(kv)?
+        .iter()
+        .position(|u| u == id)
+        .ok_or(NOT_FOUND)?;
+    kv.list_del_item(USERS_KEY, id)?;
+    release_email(kv, &user.email)?;
+    kv.del_key(&user_key(id))?;
+    Ok(idx)
 }
 
 pub(crate) fn get_user_details(
-    ctx: &CapabilitiesContext,
-    rid: &str,
+    kv: &impl KeyValue,
+    id: &str,
 ) -> std::result::Result<codec::users::User, Box<dyn std::error::Error>> {
-    let user_key = rid.replace('.', ":");
-
-    if let Some(v) = ctx.kv().get(&user_key)? {
+    if let Some(v) = kv.get(&user_key(id))? {
         match serde_json::from_str::<codec::users::User>(&v) {
             Ok(r) => Ok(r),
             Err(e) => Err(e.into()),
@@ -50,3 +179,245 @@ pub(crate) fn get_user_details(
         Err(NOT_FOUND.into())
     }
 }
+
+/// Finds the user registered with the given email address
+pub(crate) fn find_user_by_email(
+    kv: &impl KeyValue,
+    email: &str,
+) -> std::result::Result<Option<codec::users::User>, Box<dyn std::error::Error>> {
+    match kv.get(&email_key(email))? {
+        Some(id) => match get_user_details(kv, &id) {
+            Ok(user) => Ok(Some(user)),
+            Err(ref e) if e.to_string() == NOT_FOUND => Ok(None),
+            Err(e) => Err(e),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Verifies a user's credentials, returning the user if they are valid
+pub(crate) fn authenticate(
+    kv: &impl KeyValue,
+    email: &str,
+    pass: &str,
+) -> std::result::Result<Option<codec::users::User>, Box<dyn std::error::Error>> {
+    Ok(find_user_by_email(kv, email)?.filter(|u| verify_password(&u.pass, pass)))
+}
+
+/// Hashes a password with argon2, producing a PHC string that embeds the salt. The guest has
+/// no source of randomness, so the salt is derived from a store-wide sequence number along
+/// with the user's id; this keeps every salt unique, which is all a salt needs to be
+fn hash_password(
+    kv: &impl KeyValue,
+    id: &str,
+    pass: &str,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let seq = kv.atomic_add(SALT_SEQ_KEY, 1)?;
+    let mut hasher = Blake2b::<U16>::new();
+    hasher.update(seq.to_le_bytes());
+    hasher.update(id.as_bytes());
+    let salt = SaltString::encode_b64(&hasher.finalize()).map_err(|e| e.to_string())?;
+
+    let hash = Argon2::default()
+        .hash_password(pass.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?;
+    Ok(hash.to_string())
+}
+
+/// Checks a password against a stored PHC hash string. Anything that isn't a valid hash never matches
+fn verify_password(hash: &str, pass: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(pass.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        authenticate, change_password, create_user, delete_user, find_user_by_email,
+        get_user_details, get_users, grant_role, revoke_role, update_user,
+    };
+    use decscloud_common::kv::MemoryStore;
+    use decscloud_common::users::{User, ROLE_ADMIN};
+
+    fn user(id: &str, email: &str, pass: &str) -> User {
+        User {
+            id: id.to_string(),
+            email: email.to_string(),
+            pass: pass.to_string(),
+            roles: vec![],
+        }
+    }
+
+    #[test]
+    fn test_create_and_get() {
+        let kv = MemoryStore::new();
+        let (_, rid) = create_user(&kv, &user("bob", "bob@example.com", "pw")).unwrap();
+        assert_eq!(rid, "decs.user.bob");
+        assert_eq!(get_users(&kv).unwrap(), vec!["bob"]);
+        assert_eq!(
+            get_user_details(&kv, "bob").unwrap().email,
+            "bob@example.com"
+        );
+        assert_eq!(
+            get_user_details(&kv, "alice").unwrap_err().to_string(),
+            super::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "hunter2")).unwrap();
+        create_user(&kv, &user("alice", "alice@example.com", "s3cret")).unwrap();
+
+        let found = authenticate(&kv, "alice@example.com", "s3cret").unwrap();
+        assert_eq!(found.unwrap().id, "alice");
+        assert!(authenticate(&kv, "alice@example.com", "hunter2")
+            .unwrap()
+            .is_none());
+        assert!(authenticate(&kv, "carol@example.com", "s3cret")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_passwords_are_hashed() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "hunter2")).unwrap();
+        create_user(&kv, &user("alice", "alice@example.com", "hunter2")).unwrap();
+
+        let bob = get_user_details(&kv, "bob").unwrap();
+        let alice = get_user_details(&kv, "alice").unwrap();
+        assert!(bob.pass.starts_with("$argon2"));
+        assert!(!bob.pass.contains("hunter2"));
+        // Same password, different salts
+        assert_ne!(bob.pass, alice.pass);
+    }
+
+    #[test]
+    fn test_update_keeps_password() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "hunter2")).unwrap();
+        let updated = update_user(&kv, "bob", "robert@example.com").unwrap();
+        assert_eq!(updated.email, "robert@example.com");
+        assert!(authenticate(&kv, "robert@example.com", "hunter2")
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            update_user(&kv, "carol", "carol@example.com")
+                .unwrap_err()
+                .to_string(),
+            super::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_change_password() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "hunter2")).unwrap();
+
+        assert!(!change_password(&kv, "bob", Some("wrong"), "s3cret").unwrap());
+        assert!(change_password(&kv, "bob", Some("hunter2"), "s3cret").unwrap());
+        assert!(authenticate(&kv, "bob@example.com", "hunter2")
+            .unwrap()
+            .is_none());
+        assert!(authenticate(&kv, "bob@example.com", "s3cret")
+            .unwrap()
+            .is_some());
+
+        // a reset skips the check of the old password
+        assert!(change_password(&kv, "bob", None, "reset").unwrap());
+        assert!(authenticate(&kv, "bob@example.com", "reset")
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_uniqueness() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "pw")).unwrap();
+        create_user(&kv, &user("alice", "alice@example.com", "pw")).unwrap();
+
+        let dup_email = create_user(&kv, &user("robert", "Bob@Example.com", "pw"));
+        assert_eq!(dup_email.unwrap_err().to_string(), super::EMAIL_TAKEN);
+        let dup_id = create_user(&kv, &user("bob", "other@example.com", "pw"));
+        assert_eq!(dup_id.unwrap_err().to_string(), super::ID_TAKEN);
+        // A rejected id must not leave its email reserved
+        create_user(&kv, &user("carol", "other@example.com", "pw")).unwrap();
+
+        assert_eq!(
+            update_user(&kv, "alice", "bob@example.com")
+                .unwrap_err()
+                .to_string(),
+            super::EMAIL_TAKEN
+        );
+        assert_eq!(get_users(&kv).unwrap(), vec!["carol", "alice", "bob"]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "pw")).unwrap();
+        create_user(&kv, &user("alice", "alice@example.com", "pw")).unwrap();
+
+        assert_eq!(delete_user(&kv, "bob").unwrap(), 1);
+        assert_eq!(get_users(&kv).unwrap(), vec!["alice"]);
+        assert!(find_user_by_email(&kv, "bob@example.com")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            delete_user(&kv, "bob").unwrap_err().to_string(),
+            super::NOT_FOUND
+        );
+
+        // The email can be registered again once its owner is gone
+        create_user(&kv, &user("robert", "bob@example.com", "pw")).unwrap();
+        assert_eq!(
+            find_user_by_email(&kv, "BOB@example.com")
+                .unwrap()
+                .unwrap()
+                .id,
+            "robert"
+        );
+    }
+
+    #[test]
+    fn test_roles() {
+        let kv = MemoryStore::new();
+        create_user(&kv, &user("bob", "bob@example.com", "pw")).unwrap();
+        let mut root = user("root", "root@example.com", "pw");
+        root.roles = vec![ROLE_ADMIN.to_string()];
+        create_user(&kv, &root).unwrap();
+        create_user(&kv, &user("alice", "alice@example.com", "pw")).unwrap();
+        // Not even the first user is an admin unless created as one
+        assert!(get_user_details(&kv, "bob").unwrap().roles.is_empty());
+        assert_eq!(
+            get_user_details(&kv, "root").unwrap().roles,
+            vec![ROLE_ADMIN]
+        );
+        assert!(get_user_details(&kv, "alice").unwrap().roles.is_empty());
+
+        let alice = grant_role(&kv, "alice", "designer").unwrap().unwrap();
+        assert_eq!(alice.roles, vec!["designer"]);
+        assert!(grant_role(&kv, "alice", "designer").unwrap().is_none());
+        assert_eq!(
+            get_user_details(&kv, "alice").unwrap().profile().roles,
+            vec!["designer"]
+        );
+
+        assert!(revoke_role(&kv, "alice", "designer").unwrap().is_some());
+        assert!(revoke_role(&kv, "alice", "designer").unwrap().is_none());
+        assert!(get_user_details(&kv, "alice").unwrap().roles.is_empty());
+        assert_eq!(
+            grant_role(&kv, "carol", "designer")
+                .unwrap_err()
+                .to_string(),
+            super::NOT_FOUND
+        );
+    }
+}